@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+use super::handle::{ColorResult, Hand, Handle, color_result_to_index};
+
+// The file starts with a line of JSON, the header, describing the policy and
+// the opener the guesses were found with. Records follow:
+// 4..0: Index of the result of the opener
+// 8..4: Index of the result of the second guess, `SECOND` if the record is a
+//       second guess
+// 22..8: Hand of the guess
+pub const RECORD_SIZE: usize = 22;
+
+const SECOND: u32 = u32::MAX;
+
+// Guesses following the opener, keyed by the results so far
+pub struct Book {
+    header: serde_json::Value,
+    guesses: HashMap<(u32, u32), Hand>,
+}
+
+impl Book {
+    pub fn new(header: serde_json::Value) -> Self {
+        Book {
+            header,
+            guesses: HashMap::new(),
+        }
+    }
+
+    pub fn header(&self) -> &serde_json::Value {
+        &self.header
+    }
+
+    fn key(first: &ColorResult, second: Option<&ColorResult>) -> (u32, u32) {
+        (
+            color_result_to_index(first),
+            second.map_or(SECOND, color_result_to_index),
+        )
+    }
+
+    pub fn len(&self) -> usize {
+        self.guesses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.guesses.is_empty()
+    }
+
+    pub fn contains(&self, first: &ColorResult, second: Option<&ColorResult>) -> bool {
+        self.guesses.contains_key(&Self::key(first, second))
+    }
+
+    pub fn get(&self, first: &ColorResult, second: Option<&ColorResult>) -> Option<Handle> {
+        self.guesses
+            .get(&Self::key(first, second))
+            .map(|&hand| Handle {
+                hand,
+                pool: [false; 34],
+                flags: 0,
+            })
+    }
+
+    // Returns the record to append to the book file
+    pub fn insert(
+        &mut self,
+        first: &ColorResult,
+        second: Option<&ColorResult>,
+        guess: &Handle,
+    ) -> [u8; RECORD_SIZE] {
+        let key = Self::key(first, second);
+        self.guesses.insert(key, guess.hand);
+        let mut record = [0u8; RECORD_SIZE];
+        record[0..4].copy_from_slice(&key.0.to_be_bytes());
+        record[4..8].copy_from_slice(&key.1.to_be_bytes());
+        record[8..22].copy_from_slice(&guess.hand);
+        record
+    }
+
+    // The header and every record, as written to the book file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = (self.header.to_string() + "\n").into_bytes();
+        for (&(first, second), hand) in &self.guesses {
+            bytes.extend(first.to_be_bytes());
+            bytes.extend(second.to_be_bytes());
+            bytes.extend(hand);
+        }
+        bytes
+    }
+
+    // `None` without a header. A trailing partial record, left by an
+    // interrupted build, is ignored
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let end = bytes.iter().position(|&byte| byte == b'\n')?;
+        let header = serde_json::from_slice(&bytes[..end]).ok()?;
+        let guesses = bytes[end + 1..]
+            .chunks_exact(RECORD_SIZE)
+            .map(|record| {
+                let first = u32::from_be_bytes(record[0..4].try_into().unwrap());
+                let second = u32::from_be_bytes(record[4..8].try_into().unwrap());
+                ((first, second), record[8..22].try_into().unwrap())
+            })
+            .collect();
+        Some(Book { header, guesses })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::parse_color_result;
+
+    #[test]
+    fn test_book() {
+        let first = parse_color_result("GYNNNNNNNNNNNN");
+        let second = parse_color_result("NNNNGGGGNNNNNN");
+        let guess = Handle::from_string("2235m345p345888s4m");
+        let third = Handle::from_string("123456789m11p123s");
+
+        let header = serde_json::json!({ "opener": "123456789m11p123s" });
+        let mut book = Book::new(header.clone());
+        let mut bytes = book.to_bytes();
+        bytes.extend(book.insert(&first, None, &guess));
+        bytes.extend(book.insert(&first, Some(&second), &third));
+        bytes.extend([0u8; 5]);
+
+        let book = Book::from_bytes(&bytes).unwrap();
+        assert_eq!(book.header(), &header);
+        assert_eq!(book.len(), 2);
+        assert_eq!(book.get(&first, None), Some(guess));
+        assert_eq!(book.get(&first, Some(&second)), Some(third));
+        assert_eq!(book.get(&second, None), None);
+
+        let again = Book::from_bytes(&book.to_bytes()).unwrap();
+        assert_eq!(again.get(&first, Some(&second)), Some(third));
+        assert!(Book::from_bytes(&[0u8; RECORD_SIZE]).is_none());
+    }
+}
@@ -0,0 +1,73 @@
+use super::handle::{Hand, Handle};
+
+// Stands for the tiles no candidate holds, which are never green nor yellow
+// and leave the other tiles of the guess unaffected
+const ABSENT: u8 = u8::MAX;
+
+// Groups guesses splitting the candidates alike, without partitioning them.
+// Suit permutations are no such symmetry, as the results are read off the
+// positions of sorted hands
+pub struct Classes {
+    absent: [bool; 34],
+}
+
+impl Classes {
+    pub fn new(hs: &Vec<Handle>) -> Self {
+        let mut absent = [true; 34];
+        for handle in hs {
+            for &tile in &handle.hand {
+                absent[tile as usize] = false;
+            }
+        }
+        Classes { absent }
+    }
+
+    // Every guess is then alone in its class
+    pub fn is_trivial(&self) -> bool {
+        !self.absent.contains(&true)
+    }
+
+    pub fn key(&self, hand: &Hand) -> Hand {
+        hand.map(|tile| {
+            if self.absent[tile as usize] {
+                ABSENT
+            } else {
+                tile
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::test_handles;
+    use crate::mahd_fast2::partition;
+
+    #[test]
+    fn test_key() {
+        let hs = test_handles(2);
+        let guesses = [
+            "123456789m11p123s",
+            "123456789m11p123s",
+            "123456789m11p223s",
+            "123456789m11p673s",
+            "123456789m11p789s",
+        ]
+        .iter()
+        .map(|s| Handle::from_string(s))
+        .collect::<Vec<_>>();
+
+        let classes = Classes::new(&hs);
+        let uniform = [1.0; 2];
+        assert!(!classes.is_trivial());
+        assert_ne!(classes.key(&guesses[0].hand), classes.key(&guesses[4].hand));
+        for guess in &guesses[1..4] {
+            assert_eq!(classes.key(&guess.hand), classes.key(&guesses[0].hand));
+            assert_eq!(
+                partition(&hs, guess, &uniform),
+                partition(&hs, &guesses[0], &uniform)
+            );
+        }
+    }
+}
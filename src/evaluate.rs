@@ -0,0 +1,131 @@
+use super::handle::Handle;
+use super::mahd_fast2::{
+    Approximation, MetaMap, mahd_chain_entropy, mahd_chain_prepare, mahd_fast2_entropy,
+    mahd_fast2_prepare, mahd_killer_prepare,
+};
+
+// Rank of every value, 0 for the smallest, ties sharing their mean rank
+fn ranks(values: &[f64]) -> Vec<f64> {
+    let mut order = (0..values.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| values[a].partial_cmp(&values[b]).unwrap());
+    let mut ranks = vec![0.0; values.len()];
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start + 1;
+        while end < order.len() && values[order[end]] == values[order[start]] {
+            end += 1;
+        }
+        let rank = (start + end - 1) as f64 / 2.0;
+        for &i in &order[start..end] {
+            ranks[i] = rank;
+        }
+        start = end;
+    }
+    ranks
+}
+
+fn pearson(a: &[f64], b: &[f64]) -> f64 {
+    let n = a.len() as f64;
+    let mean_a = a.iter().sum::<f64>() / n;
+    let mean_b = b.iter().sum::<f64>() / n;
+    let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        cov += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a) * (x - mean_a);
+        var_b += (y - mean_b) * (y - mean_b);
+    }
+    cov / (var_a * var_b).sqrt()
+}
+
+// Spearman rank correlation, 1 if `a` and `b` order the items alike
+pub fn spearman(a: &[f64], b: &[f64]) -> f64 {
+    assert_eq!(a.len(), b.len());
+    pearson(&ranks(a), &ranks(b))
+}
+
+// Top-k sizes reported by `evaluate`
+pub const RECALL_K: [usize; 3] = [1, 10, 100];
+
+// How the approximation ranks `guesses` over the candidates, against their
+// exact partition entropy
+#[derive(Debug)]
+pub struct Evaluation {
+    pub size: usize,
+    pub correlation: f64,
+    // Share of the exact top k found in the approximate top k, per `RECALL_K`
+    pub recall: Vec<f64>,
+    // Guesses to take from the approximate ranking to hold an exact best one
+    pub needed: usize,
+}
+
+fn approximate(
+    hs: &Vec<Handle>,
+    guesses: &Vec<Handle>,
+    approximation: Approximation,
+    weights: &[f64],
+) -> Vec<f64> {
+    let meta_map = MetaMap::from_handles(|| (), hs, weights);
+    let hes = match approximation {
+        Approximation::Pairs => mahd_fast2_entropy(|| (), guesses, &mahd_fast2_prepare(&meta_map)),
+        Approximation::Chain => mahd_chain_entropy(|| (), guesses, &mahd_chain_prepare(&meta_map)),
+    };
+    hes.iter().map(|he| he.entropy).collect()
+}
+
+// Indices of `values`, largest first
+fn descending(values: &[f64]) -> Vec<usize> {
+    let mut order = (0..values.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| values[b].partial_cmp(&values[a]).unwrap());
+    order
+}
+
+// `weights` are those of `hs`, in the same order
+pub fn evaluate(
+    hs: &Vec<Handle>,
+    guesses: &Vec<Handle>,
+    approximation: Approximation,
+    weights: &[f64],
+) -> Evaluation {
+    let exact = mahd_killer_prepare(|| (), hs, guesses, weights)
+        .iter()
+        .map(|score| score.entropy)
+        .collect::<Vec<_>>();
+    let approx = approximate(hs, guesses, approximation, weights);
+
+    let exact_order = descending(&exact);
+    let approx_order = descending(&approx);
+    let recall = RECALL_K
+        .iter()
+        .map(|&k| {
+            let k = k.min(guesses.len());
+            let found = approx_order[..k]
+                .iter()
+                .filter(|i| exact_order[..k].contains(i))
+                .count();
+            found as f64 / k as f64
+        })
+        .collect();
+    let best = exact[exact_order[0]];
+    let needed = approx_order.iter().position(|&i| exact[i] == best).unwrap() + 1;
+
+    Evaluation {
+        size: hs.len(),
+        correlation: spearman(&approx, &exact),
+        recall,
+        needed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spearman() {
+        let a = [1.0, 2.0, 3.0, 4.0, 5.0];
+        assert!((spearman(&a, &[2.0, 4.0, 8.0, 16.0, 32.0]) - 1.0).abs() < 1e-9);
+        assert!((spearman(&a, &[5.0, 4.0, 3.0, 2.0, 1.0]) + 1.0).abs() < 1e-9);
+        assert_eq!(ranks(&[3.0, 1.0, 3.0, 2.0]), vec![2.5, 0.0, 2.5, 1.0]);
+        assert!((spearman(&a, &[1.0, 3.0, 2.0, 4.0, 5.0]) - 0.9).abs() < 1e-9);
+    }
+}
@@ -1,22 +1,95 @@
-use super::handle::Handle;
+use super::handle::{Context, Handle};
 use super::store;
-use super::utils::{koutsu_of_tile, next_tile, shuntsu_of_tile, toitsu_of_tile};
+use super::utils::{all_tiles, koutsu_of_tile, next_tile, shuntsu_of_tile, toitsu_of_tile};
 use riichi::agenda::AgendaName;
 use riichi::hand::PartitionedHand;
 use riichi::score::Score;
 use riichi::tile::Tile;
+use std::collections::BTreeMap;
 use std::iter::Iterator;
-use std::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender, channel, sync_channel};
 
 #[derive(Clone)]
 pub struct Finder {
     partitions: PartitionedHand,
     pool: [u8; 34],
     winning_tile: Option<Tile>,
+    wait: Option<Wait>,
     toitsu: Option<Tile>,
     nmentsu: u8,
     stage: FinderStage,
     curr: Tile,
+    only_toitsu: Option<Tile>,
+    only_winning_tile: Option<Tile>,
+    context: Option<Context>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Shape {
+    Standard,
+    Chiitoi,
+    Kokushi,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Wait {
+    Ryanmen,
+    Kanchan,
+    Penchan,
+    Shanpon,
+    Tanki,
+}
+
+// What the finders send for each hand they find
+pub trait Found: Send + 'static {
+    // `flags` are those of the finder's decomposition, already scored
+    fn standard(finder: Finder, flags: u8) -> Self;
+    fn special(handle: Handle, shape: Shape) -> Self;
+}
+
+impl Found for Handle {
+    fn standard(finder: Finder, flags: u8) -> Self {
+        finder.into_handle(flags)
+    }
+
+    fn special(handle: Handle, _shape: Shape) -> Self {
+        handle
+    }
+}
+
+// A hand together with the decomposition it was found by
+#[derive(Debug, Clone)]
+pub struct Detail {
+    pub handle: Handle,
+    pub shape: Shape,
+    pub wait: Wait,
+    pub yaku: Vec<String>,
+}
+
+impl Found for Detail {
+    fn standard(finder: Finder, flags: u8) -> Self {
+        Detail {
+            shape: Shape::Standard,
+            wait: finder.wait.unwrap(),
+            yaku: Finder::get_yaku(finder.partitions.clone()),
+            handle: finder.into_handle(flags),
+        }
+    }
+
+    fn special(handle: Handle, shape: Shape) -> Self {
+        let yaku = match shape {
+            Shape::Kokushi => "Kokushi",
+            _ => "Chiitoi",
+        };
+        Detail {
+            handle,
+            shape,
+            wait: Wait::Tanki,
+            yaku: vec![yaku.to_string()],
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -36,10 +109,25 @@ impl Finder {
             },
             pool: [0; 34],
             winning_tile: None,
+            wait: None,
             toitsu: None,
             nmentsu: 0,
             stage: FinderStage::Toitsu,
             curr: Tile::_1m,
+            only_toitsu: None,
+            only_winning_tile: None,
+            context: None,
+        }
+    }
+
+    // Only finds the hands with the given toitsu and winning tile, and valid
+    // in the given context
+    pub fn restricted(toitsu: Tile, winning_tile: Tile, context: Option<Context>) -> Self {
+        Finder {
+            only_toitsu: Some(toitsu),
+            only_winning_tile: Some(winning_tile),
+            context,
+            ..Finder::new()
         }
     }
 
@@ -59,10 +147,26 @@ impl Finder {
         !self.need_mentsu() && !self.need_toitsu() && self.winning_tile.is_some()
     }
 
-    pub fn next(self, tx: &Sender<Handle>) {
+    fn allow_toitsu(&self) -> bool {
+        self.only_toitsu.is_none_or(|tile| tile == self.curr)
+    }
+
+    fn allow_winning_tile(&self, tile: Tile) -> bool {
+        self.winning_tile.is_none() && self.only_winning_tile.is_none_or(|only| only == tile)
+    }
+
+    // The restricted toitsu or winning tile can not be taken after `curr`
+    fn stranded(&self) -> bool {
+        let curr = self.curr as u8;
+        self.need_toitsu() && self.only_toitsu.is_some_and(|tile| curr >= tile as u8)
+            || self.winning_tile.is_none()
+                && self.only_winning_tile.is_some_and(|tile| curr >= tile as u8)
+    }
+
+    pub fn next<T: Found>(self, tx: &Sender<T>) {
         match self.stage {
             FinderStage::Toitsu => {
-                if self.need_toitsu() && self.curr_num() <= 2 {
+                if self.need_toitsu() && self.curr_num() <= 2 && self.allow_toitsu() {
                     let mut finder = self.clone();
                     finder.stage = FinderStage::Shuntsu;
                     finder
@@ -71,9 +175,10 @@ impl Finder {
                         .push((toitsu_of_tile(finder.curr), false));
                     finder.toitsu = Some(finder.curr);
                     finder.pool[finder.curr as usize] += 2;
-                    if finder.winning_tile.is_none() {
+                    if finder.allow_winning_tile(finder.curr) {
                         let mut finder = finder.clone();
                         finder.winning_tile = Some(finder.curr);
+                        finder.wait = Some(Wait::Tanki);
                         finder.partitions.is_singular_wait = true;
                         finder.next(tx);
                     }
@@ -91,13 +196,14 @@ impl Finder {
                     finder.stage = FinderStage::Shuntsu;
                     finder.nmentsu += 1;
                     finder.pool[finder.curr as usize] += 3;
-                    if finder.winning_tile.is_none() {
+                    if finder.allow_winning_tile(finder.curr) {
                         let mut finder = finder.clone();
                         finder
                             .partitions
                             .group_items
                             .push((koutsu_of_tile(finder.curr), true));
                         finder.winning_tile = Some(finder.curr);
+                        finder.wait = Some(Wait::Shanpon);
                         finder.next(tx);
                     }
                     finder
@@ -124,19 +230,31 @@ impl Finder {
                     finder.pool[finder.curr as usize] += 1;
                     finder.pool[finder.curr as usize + 1] += 1;
                     finder.pool[finder.curr as usize + 2] += 1;
-                    if finder.winning_tile.is_none() {
+                    let snd = next_tile(finder.curr).unwrap();
+                    let trd = next_tile(snd).unwrap();
+                    let edge = |tile: Tile| match tile as u8 % 9 {
+                        0 | 8 => Wait::Penchan,
+                        _ => Wait::Ryanmen,
+                    };
+                    if finder.allow_winning_tile(finder.curr) {
                         let mut finder1 = finder.clone();
                         finder1.winning_tile = Some(finder1.curr);
+                        finder1.wait = Some(edge(trd));
                         finder1.partitions.is_singular_wait =
                             [Tile::_1m, Tile::_1p, Tile::_1s].contains(&finder1.curr);
                         finder1.next(tx);
+                    }
+                    if finder.allow_winning_tile(snd) {
                         let mut finder2 = finder.clone();
-                        finder2.winning_tile = Some(next_tile(finder2.curr).unwrap());
+                        finder2.winning_tile = Some(snd);
+                        finder2.wait = Some(Wait::Kanchan);
                         finder2.partitions.is_singular_wait = true;
                         finder2.next(tx);
+                    }
+                    if finder.allow_winning_tile(trd) {
                         let mut finder3 = finder.clone();
-                        finder3.winning_tile =
-                            Some(next_tile(next_tile(finder3.curr).unwrap()).unwrap());
+                        finder3.winning_tile = Some(trd);
+                        finder3.wait = Some(edge(finder.curr));
                         finder3.partitions.is_singular_wait =
                             [Tile::_1m, Tile::_1p, Tile::_1s].contains(&finder3.curr);
                         finder3.next(tx);
@@ -151,9 +269,18 @@ impl Finder {
             }
             FinderStage::Check => {
                 if self.finished() {
-                    let handle = self.to_handle();
-                    tx.send(handle).unwrap();
-                } else {
+                    // A yaku valid in every context, such as a dragon koutsu,
+                    // stays possible until the last group: only the scoring of
+                    // the whole decomposition tells, and a rejected one is
+                    // neither scored again nor built
+                    let flags = Self::get_flags(self.partitions.clone());
+                    if self
+                        .context
+                        .is_none_or(|context| context.check_flags(flags))
+                    {
+                        tx.send(T::standard(self, flags)).unwrap();
+                    }
+                } else if !self.stranded() {
                     match next_tile(self.curr) {
                         Some(tile) => {
                             Finder {
@@ -170,6 +297,16 @@ impl Finder {
         }
     }
 
+    fn get_yaku(partitions: PartitionedHand) -> Vec<String> {
+        match partitions.calculate_score(riichi::agendas_template::AGENDAS_TEMPLATE) {
+            Score::Done => vec!["Done".to_string()],
+            Score::Name(agenda_names) => agenda_names
+                .iter()
+                .map(|name| format!("{:?}", name))
+                .collect(),
+        }
+    }
+
     fn get_flags(partitions: PartitionedHand) -> u8 {
         let mut flags: u8 = 0;
         match partitions.calculate_score(riichi::agendas_template::AGENDAS_TEMPLATE) {
@@ -224,7 +361,12 @@ impl Finder {
         flags
     }
 
-    pub fn to_handle(mut self) -> Handle {
+    pub fn to_handle(self) -> Handle {
+        let flags = Self::get_flags(self.partitions.clone());
+        self.into_handle(flags)
+    }
+
+    fn into_handle(mut self, flags: u8) -> Handle {
         // Handle.pool
         let mut pool = [false; 34];
         for i in 0..34 {
@@ -245,8 +387,6 @@ impl Finder {
         assert!(waiting_hand_index == 13);
         hand[13] = winning_tile as u8;
 
-        // Handle.flags
-        let flags = Self::get_flags(self.partitions);
         Handle { hand, pool, flags }
     }
 }
@@ -269,21 +409,34 @@ impl ChiitoiFinder {
         }
     }
 
+    // Only finds the hands whose two lowest pairs are `first` and `second`
+    pub fn restricted(first: u8, second: u8) -> Self {
+        let mut finder = ChiitoiFinder::new();
+        for tile in [first, second] {
+            finder.hand[finder.npairs * 2] = tile;
+            finder.hand[finder.npairs * 2 + 1] = tile;
+            finder.pool[tile as usize] = true;
+            finder.npairs += 1;
+        }
+        finder.curr = second + 1;
+        finder
+    }
+
     fn finished(&self) -> bool {
         self.npairs == 7
     }
 
-    pub fn next(mut self, tx: &Sender<Handle>) {
+    pub fn next<T: Found>(mut self, tx: &Sender<T>) {
         if self.finished() {
             let mut handle = Handle {
                 hand: self.hand,
                 pool: self.pool,
                 flags: store::MASK_TRUE_ALWAYS,
             };
-            tx.send(handle.clone()).unwrap();
+            tx.send(T::special(handle.clone(), Shape::Chiitoi)).unwrap();
             for i in 1..6 {
                 handle.hand.swap(13 - 2 * i, 13);
-                tx.send(handle.clone()).unwrap();
+                tx.send(T::special(handle.clone(), Shape::Chiitoi)).unwrap();
             }
         } else if self.curr < 34 {
             ChiitoiFinder {
@@ -342,20 +495,20 @@ impl KokushiFinder {
         KokushiFinder { pool, hand }
     }
 
-    fn next_helper(self, tx: &Sender<Handle>) {
+    fn next_helper<T: Found>(self, tx: &Sender<T>) {
         let mut handle = Handle {
             hand: self.hand,
             pool: self.pool,
             flags: store::MASK_TRUE_ALWAYS,
         };
-        tx.send(handle.clone()).unwrap();
+        tx.send(T::special(handle.clone(), Shape::Kokushi)).unwrap();
         for i in 1..14 {
             handle.hand.swap(13 - i, 13);
-            tx.send(handle.clone()).unwrap();
+            tx.send(T::special(handle.clone(), Shape::Kokushi)).unwrap();
         }
     }
 
-    pub fn next(self, tx: &Sender<Handle>) {
+    pub fn next<T: Found>(self, tx: &Sender<T>) {
         for i in 1..13 {
             let mut finder = self.clone();
             finder.hand[i] = finder.hand[i + 1];
@@ -364,32 +517,139 @@ impl KokushiFinder {
     }
 }
 
-pub struct Generator {
-    rx: Receiver<Handle>,
+// Number of finished units buffered ahead of the consumer
+const UNIT_BUFFER: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Unit {
+    Standard { toitsu: Tile, winning_tile: Tile },
+    // Split by the two lowest pairs, as a single unit would hold every one
+    // of the tens of millions of chiitoi records at once
+    Chiitoi { first: u8, second: u8 },
+    Kokushi,
+}
+
+impl Unit {
+    // Every hand is found by exactly one unit, in this order
+    pub fn all() -> Vec<Unit> {
+        let mut units = vec![];
+        // Grouped by winning tile, which all decompositions of a hand share
+        for winning_tile in all_tiles() {
+            for toitsu in all_tiles() {
+                units.push(Unit::Standard {
+                    toitsu,
+                    winning_tile,
+                });
+            }
+        }
+        for first in 0..34 {
+            for second in first + 1..34 {
+                units.push(Unit::Chiitoi { first, second });
+            }
+        }
+        units.push(Unit::Kokushi);
+        units
+    }
+
+    // Chiitoi and kokushi are valid in any context
+    pub fn run<T: Found>(self, context: Option<Context>) -> Vec<T> {
+        let (tx, rx) = channel();
+        match self {
+            Unit::Standard {
+                toitsu,
+                winning_tile,
+            } => Finder::restricted(toitsu, winning_tile, context).next(&tx),
+            Unit::Chiitoi { first, second } => ChiitoiFinder::restricted(first, second).next(&tx),
+            Unit::Kokushi => KokushiFinder::new().next(&tx),
+        }
+        drop(tx);
+        rx.into_iter().collect()
+    }
+}
+
+pub struct Generator<T = Handle> {
+    rx: Receiver<(usize, Vec<T>)>,
+    buffer: std::vec::IntoIter<T>,
 }
 
 impl Generator {
-    // Use this to send partitioned hands to `next()` through channels
     pub fn new() -> Self {
-        let (tx, rx) = channel();
-        let tx_ = tx.clone();
-        std::thread::spawn(move || Finder::new().next(&tx_));
-        let tx_ = tx.clone();
-        std::thread::spawn(move || ChiitoiFinder::new().next(&tx_));
-        let tx_ = tx.clone();
-        std::thread::spawn(move || KokushiFinder::new().next(&tx_));
-        Self { rx }
+        Self::spawn(0, None)
+    }
+
+    // Skips the units before `first_unit`, which are already done
+    pub fn resume(first_unit: usize, context: Option<Context>) -> Self {
+        Self::spawn(first_unit, context)
     }
 }
 
-impl Iterator for Generator {
-    type Item = Handle;
+impl<T: Found> Generator<T> {
+    // Runs the units on a thread pool and yields their hands in unit order,
+    // leaving out the hands not valid in `context`
+    pub fn spawn(first_unit: usize, context: Option<Context>) -> Self {
+        let units = Arc::new(Unit::all());
+        let next_unit = Arc::new(AtomicUsize::new(first_unit));
+        let n_workers = std::thread::available_parallelism().map_or(1, |n| n.get());
 
-    fn next(&mut self) -> Option<Self::Item> {
+        let (worker_tx, worker_rx) = sync_channel(n_workers);
+        for _ in 0..n_workers {
+            let units = units.clone();
+            let next_unit = next_unit.clone();
+            let worker_tx = worker_tx.clone();
+            std::thread::spawn(move || {
+                loop {
+                    let index = next_unit.fetch_add(1, Ordering::Relaxed);
+                    if index >= units.len() {
+                        break;
+                    }
+                    if worker_tx.send((index, units[index].run(context))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(worker_tx);
+
+        let (tx, rx) = sync_channel(UNIT_BUFFER);
+        std::thread::spawn(move || {
+            let mut pending = BTreeMap::new();
+            let mut expected = first_unit;
+            for (index, handles) in worker_rx {
+                pending.insert(index, handles);
+                while let Some(handles) = pending.remove(&expected) {
+                    if tx.send((expected, handles)).is_err() {
+                        return;
+                    }
+                    expected += 1;
+                }
+            }
+        });
+
+        Self {
+            rx,
+            buffer: vec![].into_iter(),
+        }
+    }
+
+    // Yields the next whole unit with its index, not to be mixed with `next`
+    pub fn next_unit(&mut self) -> Option<(usize, Vec<T>)> {
         self.rx.recv().ok()
     }
 }
 
+impl<T: Found> Iterator for Generator<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(handle) = self.buffer.next() {
+                return Some(handle);
+            }
+            self.buffer = self.next_unit()?.1.into_iter();
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -447,7 +707,7 @@ mod test {
             is_singular_wait: false,
         };
         let flags = Finder::get_flags(partitions);
-        assert_eq!(flags, 0b00000000);
+        assert_eq!(flags, store::NOT_ANY_TON);
 
         let partitions = PartitionedHand {
             group_items: vec![
@@ -460,7 +720,7 @@ mod test {
             is_singular_wait: false,
         };
         let flags = Finder::get_flags(partitions);
-        assert_eq!(flags, 0b00110000);
+        assert_eq!(flags, store::NOT_ANY_PEI);
     }
 
     #[test]
@@ -478,4 +738,62 @@ mod test {
         let flags = Finder::get_flags(partitions);
         assert_eq!(flags, 0b00000111);
     }
+
+    #[test]
+    fn test_merge_partitions() {
+        // 11z 234m 345m 123p 789s won on 4m: ryanmen on 234m or kanchan on 345m
+        let ryanmen = PartitionedHand {
+            group_items: vec![
+                (Toitsu(_11z), false),
+                (Shuntsu(_234m), false),
+                (Shuntsu(_345m), false),
+                (Shuntsu(_123p), false),
+                (Shuntsu(_789s), false),
+            ],
+            is_singular_wait: false,
+        };
+        let kanchan = PartitionedHand {
+            is_singular_wait: true,
+            ..ryanmen.clone()
+        };
+        let flags =
+            store::merge_flags(Finder::get_flags(ryanmen), Finder::get_flags(kanchan)).unwrap();
+        assert_eq!(flags, store::NOT_ANY_TON | store::MASK_FALSE_IF_RON);
+        assert!(Context::parse_context("").check_flags(flags));
+        assert!(!Context::parse_context("e").check_flags(flags));
+        assert!(Context::parse_context("t").check_flags(flags));
+        assert!(Context::parse_context("te").check_flags(flags));
+    }
+
+    #[test]
+    fn test_unit() {
+        let unit = Unit::Standard {
+            toitsu: Tile::_5z,
+            winning_tile: Tile::_9s,
+        };
+        let handles: Vec<Handle> = unit.run(None);
+        assert!(!handles.is_empty());
+        assert!(handles.iter().all(|handle| {
+            handle.hand[13] == Tile::_9s as u8
+                && handle.hand.iter().filter(|&&tile| tile == Tile::_5z as u8).count() >= 2
+        }));
+        assert_eq!(handles, unit.run(None));
+        assert_eq!(Unit::Kokushi.run::<Handle>(None).len(), 12 * 14);
+        let pairs = |first, second| Unit::Chiitoi { first, second }.run::<Handle>(None).len();
+        // The five other pairs among the 32 tiles above 1m, 2m then 1m, 3m
+        assert_eq!(pairs(0, 1), 201376 * 6);
+        assert_eq!(pairs(0, 2), 169911 * 6);
+
+        let context = Context::parse_context("tn");
+        let filtered: Vec<Handle> = unit.run(Some(context));
+        assert!(filtered.len() < handles.len());
+        assert!(filtered.iter().all(|handle| handle.match_context(&context)));
+        assert_eq!(
+            filtered,
+            handles
+                .into_iter()
+                .filter(|handle| handle.match_context(&context))
+                .collect::<Vec<_>>()
+        );
+    }
 }
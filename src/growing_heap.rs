@@ -1,73 +1,256 @@
 use std::cmp::Ord;
-use std::collections::{BinaryHeap, HashSet};
-use std::hash::Hash;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 
-struct UniqueHeap<T>
-where
-    T: Ord + Hash + Clone,
-{
-    heap: BinaryHeap<T>,
+// Items seen so far, so that they are only pushed once
+pub trait Visited<T> {
+    // Whether `item` is new, remembering it
+    fn insert(&mut self, item: &T) -> bool;
+}
+
+// Remembers every item
+pub struct Unbounded<T> {
     set: HashSet<T>,
 }
 
-impl<T> UniqueHeap<T>
-where
-    T: Ord + Hash + Clone,
-{
-    fn new() -> Self {
+impl<T> Unbounded<T> {
+    pub fn new() -> Self {
         Self {
-            heap: BinaryHeap::new(),
             set: HashSet::new(),
         }
     }
+}
 
-    fn push(&mut self, item: T) {
-        if self.set.contains(&item) {
-            return;
+impl<T: Hash + Eq + Clone> Visited<T> for Unbounded<T> {
+    fn insert(&mut self, item: &T) -> bool {
+        self.set.insert(item.clone())
+    }
+}
+
+// Fixed memory, but some new items are taken for seen ones
+pub struct Bloom {
+    bits: Vec<u64>,
+    hashes: u64,
+}
+
+impl Bloom {
+    // `bits` is rounded up to whole words
+    pub fn new(bits: usize, hashes: u64) -> Self {
+        assert!(bits > 0);
+        Self {
+            bits: vec![0; bits.div_ceil(64)],
+            hashes,
+        }
+    }
+}
+
+impl<T: Hash> Visited<T> for Bloom {
+    fn insert(&mut self, item: &T) -> bool {
+        let len = self.bits.len() as u64 * 64;
+        let mut new = false;
+        for seed in 0..self.hashes {
+            let mut hasher = DefaultHasher::new();
+            seed.hash(&mut hasher);
+            item.hash(&mut hasher);
+            let bit = hasher.finish() % len;
+            let word = &mut self.bits[(bit / 64) as usize];
+            new |= *word & (1 << (bit % 64)) == 0;
+            *word |= 1 << (bit % 64);
+        }
+        new
+    }
+}
+
+// Remembers the `capacity` items seen last, older ones may be pushed again
+pub struct Lru<T> {
+    capacity: usize,
+    clock: u64,
+    seen: HashMap<T, u64>,
+    order: BTreeMap<u64, T>,
+}
+
+impl<T> Lru<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            clock: 0,
+            seen: HashMap::new(),
+            order: BTreeMap::new(),
         }
-        self.set.insert(item.clone());
-        self.heap.push(item);
     }
+}
 
-    fn pop(&mut self) -> Option<T> {
-        // self.set.remove(&item);
-        self.heap.pop()
+impl<T: Hash + Eq + Clone> Visited<T> for Lru<T> {
+    fn insert(&mut self, item: &T) -> bool {
+        self.clock += 1;
+        if let Some(last) = self.seen.insert(item.clone(), self.clock) {
+            self.order.remove(&last);
+            self.order.insert(self.clock, item.clone());
+            return false;
+        }
+        self.order.insert(self.clock, item.clone());
+        if self.seen.len() > self.capacity {
+            let (_, oldest) = self.order.pop_first().unwrap();
+            self.seen.remove(&oldest);
+        }
+        true
     }
 }
 
-pub trait Growable {
-    fn grow(&self) -> impl Iterator<Item = Self>;
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counters {
+    // Items popped and grown
+    pub expansions: usize,
+    pub pushed: usize,
+    // Items grown again, or taken for seen ones by the visited set
+    pub duplicates: usize,
+    // Worst items dropped to keep to the beam width
+    pub evicted: usize,
 }
 
-pub struct GrowingHeap<T>
+// Items are built from their keys, so that a key already visited costs
+// nothing to grow again. `Ord` must only find the same item `Equal`, as the
+// frontier keeps one item of each order.
+pub trait Growable: Sized {
+    type Key: Hash + Eq + Clone;
+
+    fn key(&self) -> Self::Key;
+
+    // Keys of the items one step away
+    fn grow(&self) -> impl Iterator<Item = Self::Key>;
+
+    fn build(&self, key: Self::Key) -> Self;
+}
+
+// Yields the best item pushed so far, pushing the items it grows
+pub struct GrowingHeap<T, V = Unbounded<<T as Growable>::Key>>
 where
-    T: Ord + Hash + Clone + Growable,
+    T: Ord + Growable,
+    V: Visited<T::Key>,
 {
-    uh: UniqueHeap<T>,
+    frontier: BTreeSet<T>,
+    visited: V,
+    beam: usize,
+    counters: Counters,
 }
 
 impl<T> GrowingHeap<T>
 where
-    T: Ord + Hash + Clone + Growable,
+    T: Ord + Growable,
 {
     pub fn new(seed: T) -> Self {
-        let mut uh = UniqueHeap::new();
-        uh.push(seed);
-        Self { uh }
+        Self::bounded(seed, Unbounded::new(), usize::MAX)
+    }
+}
+
+impl<T, V> GrowingHeap<T, V>
+where
+    T: Ord + Growable,
+    V: Visited<T::Key>,
+{
+    // At most `beam` items wait to be popped
+    pub fn bounded(seed: T, visited: V, beam: usize) -> Self {
+        let mut heap = Self {
+            frontier: BTreeSet::new(),
+            visited,
+            beam,
+            counters: Counters::default(),
+        };
+        heap.visited.insert(&seed.key());
+        heap.push(seed);
+        heap
+    }
+
+    fn push(&mut self, item: T) {
+        if !self.frontier.insert(item) {
+            self.counters.duplicates += 1;
+            return;
+        }
+        self.counters.pushed += 1;
+        if self.frontier.len() > self.beam {
+            self.frontier.pop_first();
+            self.counters.evicted += 1;
+        }
+    }
+
+    pub fn counters(&self) -> &Counters {
+        &self.counters
     }
 }
 
-impl<T> Iterator for GrowingHeap<T>
+impl<T, V> Iterator for GrowingHeap<T, V>
 where
-    T: Ord + Hash + Clone + Growable,
+    T: Ord + Growable,
+    V: Visited<T::Key>,
 {
     type Item = T;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let item = self.uh.pop()?;
-        for new_item in item.grow() {
-            self.uh.push(new_item);
+        let item = self.frontier.pop_last()?;
+        self.counters.expansions += 1;
+        for key in item.grow() {
+            if self.visited.insert(&key) {
+                self.push(item.build(key));
+            } else {
+                self.counters.duplicates += 1;
+            }
         }
         Some(item)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Numbers below 100, each growing the next two
+    #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    struct Number(u32);
+
+    impl Growable for Number {
+        type Key = u32;
+
+        fn key(&self) -> u32 {
+            self.0
+        }
+
+        fn grow(&self) -> impl Iterator<Item = u32> {
+            [self.0 + 1, self.0 + 2].into_iter().filter(|&n| n < 100)
+        }
+
+        fn build(&self, key: u32) -> Self {
+            Number(key)
+        }
+    }
+
+    #[test]
+    fn test_growing_heap() {
+        let mut heap = GrowingHeap::new(Number(0));
+        let mut items = heap.by_ref().map(|n| n.0).collect::<Vec<_>>();
+        items.sort();
+        assert_eq!(items, (0..100).collect::<Vec<_>>());
+        assert_eq!(heap.counters().expansions, 100);
+        assert_eq!(heap.counters().pushed, 100);
+        assert_eq!(heap.counters().evicted, 0);
+
+        // Only the best item waits, the odd ones are dropped on the way
+        let mut heap = GrowingHeap::bounded(Number(0), Unbounded::new(), 1);
+        let items = heap.by_ref().map(|n| n.0).collect::<Vec<_>>();
+        assert_eq!(items.len(), 51);
+        assert_eq!(items[50], 99);
+        assert_eq!(heap.counters().evicted, 49);
+
+        // Forgotten items are pushed again, none is missed
+        let mut heap = GrowingHeap::bounded(Number(0), Lru::new(8), usize::MAX);
+        let items = heap.by_ref().map(|n| n.0).collect::<Vec<_>>();
+        assert!(items.len() > 100);
+        assert_eq!(items.iter().collect::<HashSet<_>>().len(), 100);
+
+        // Items taken for seen ones are missed, none is grown twice
+        let mut heap = GrowingHeap::bounded(Number(0), Bloom::new(64, 2), usize::MAX);
+        let items = heap.by_ref().map(|n| n.0).collect::<Vec<_>>();
+        assert!(items.len() < 100);
+        assert_eq!(items.len(), items.iter().collect::<HashSet<_>>().len());
+    }
+}
@@ -24,6 +24,19 @@ pub fn color_result_to_index(color_result: &ColorResult) -> u32 {
     })
 }
 
+pub fn index_to_color_result(mut index: u32) -> ColorResult {
+    let mut result = [Color::Green; 14];
+    for color in result.iter_mut().rev() {
+        *color = match index % 3 {
+            0 => Color::Green,
+            1 => Color::Yellow,
+            _ => Color::None,
+        };
+        index /= 3;
+    }
+    result
+}
+
 pub fn parse_color_result(s: &str) -> ColorResult {
     let mut result = [Color::None; 14];
     for (i, c) in s.chars().enumerate() {
@@ -70,29 +83,56 @@ impl Context {
         }
     }
 
+    fn winds(&self) -> u8 {
+        (self.east as u8) << 0
+            | (self.south as u8) << 1
+            | (self.west as u8) << 2
+            | (self.north as u8) << 3
+    }
+
     pub fn check_flags(&self, flags: u8) -> bool {
         if flags & store::MASK_TRUE_ALWAYS != 0 {
-            true
-        } else if flags & store::MASK_FALSE_IF_RON != 0 && self.tsumo {
-            false
-        } else if flags & store::MASK_GROUP_ANY != 0 {
-            flags
-                & ((self.east as u8) << 0
-                    | (self.south as u8) << 1
-                    | (self.west as u8) << 2
-                    | (self.north as u8) << 3)
-                != 0
+            return true;
+        }
+        if flags & store::MASK_FALSE_IF_RON != 0 && self.tsumo {
+            return true;
+        }
+        let winds = flags & store::MASK_GROUP_ANY;
+        if flags & store::MASK_NOT_ANY != 0 {
+            winds & !self.winds() != 0
         } else {
-            match flags & store::MASK_GROUP_NOT_ANY {
-                store::NOT_ANY_TON => !self.east,
-                store::NOT_ANY_NAN => !self.south,
-                store::NOT_ANY_SHA => !self.west,
-                store::NOT_ANY_PEI => !self.north,
-                _ => unreachable!(),
-            }
+            winds & self.winds() != 0
         }
     }
 
+    // Every combination of tsumo and winds
+    pub fn all() -> Vec<Self> {
+        (0..32u8)
+            .map(|bits| Context {
+                tsumo: bits & 1 != 0,
+                east: bits & 2 != 0,
+                south: bits & 4 != 0,
+                west: bits & 8 != 0,
+                north: bits & 16 != 0,
+            })
+            .collect()
+    }
+
+    // Inverse of `parse_context`, in canonical order
+    pub fn key(&self) -> String {
+        [
+            (self.tsumo, 't'),
+            (self.east, 'e'),
+            (self.south, 's'),
+            (self.west, 'w'),
+            (self.north, 'n'),
+        ]
+        .iter()
+        .filter(|(on, _)| *on)
+        .map(|(_, c)| c)
+        .collect()
+    }
+
     pub fn parse_context(s: &str) -> Self {
         let mut context = Context::new();
         for c in s.chars() {
@@ -172,7 +212,7 @@ impl Handle {
         Handle {
             hand: store::get_hand(&info),
             pool: store::get_pool(&info),
-            flags: (info & 0b11111111) as u8,
+            flags: store::migrate_flags((info & 0b11111111) as u8),
         }
     }
 
@@ -208,8 +248,12 @@ impl Handle {
     pub fn handle_to_string(handle: &Handle) -> String {
         let mut result = Self::hand_to_string(&handle.hand);
         result.push_str(" ".repeat(20 - result.len()).as_str());
-        if handle.flags & store::MASK_FALSE_IF_RON != 0 {
+        if handle.flags == store::MASK_FALSE_IF_RON {
             result.push_str(" _TSUMO__");
+        } else if handle.flags & store::MASK_FALSE_IF_RON != 0 {
+            // Tsumo, or the wind clause of another decomposition
+            let winds = handle.flags & !store::MASK_FALSE_IF_RON;
+            result.push_str(format!(" _TSUMO__ {:08b}", winds).as_str());
         } else if handle.flags & store::MASK_TRUE_ALWAYS != 0 {
             result.push_str(" _ALWAYS_");
         } else {
@@ -219,6 +263,14 @@ impl Handle {
     }
 
     pub fn from_string(s: &str) -> Self {
+        let hand = Self::parse_hand(s).unwrap();
+        let pool = [false; 34];
+        let flags = MASK_TRUE_ALWAYS;
+        Handle { hand, pool, flags }
+    }
+
+    // None unless `s` spells exactly 14 tiles, each followed by its suit
+    pub fn parse_hand(s: &str) -> Option<Hand> {
         let mut hand = vec![];
         let mut buf = vec![];
         for c in s.chars() {
@@ -240,10 +292,10 @@ impl Handle {
                 _ => {}
             }
         }
-        let hand = hand.try_into().unwrap();
-        let pool = [false; 34];
-        let flags = MASK_TRUE_ALWAYS;
-        Handle { hand, pool, flags }
+        if !buf.is_empty() || hand.iter().any(|&tile| tile >= 34) {
+            return None;
+        }
+        hand.try_into().ok()
     }
 
     pub fn match_context(&self, context: &Context) -> bool {
@@ -309,6 +361,34 @@ impl Handle {
     }
 }
 
+// Hands the tests of several modules share, the last one seven pairs
+#[cfg(test)]
+pub const TEST_HANDS: [&str; 4] = [
+    "2235m345p345888s4m",
+    "2245567789m123p3m",
+    "123456789m11p123s",
+    "11223344556677z",
+];
+
+#[cfg(test)]
+impl Handle {
+    // A hand whose pool holds its own tiles, as the generator leaves it
+    pub fn with_pool(s: &str) -> Self {
+        let handle = Handle::from_string(s);
+        let pool = handle.hand.iter().fold([false; 34], |mut pool, &tile| {
+            pool[tile as usize] = true;
+            pool
+        });
+        Handle { pool, ..handle }
+    }
+}
+
+// The first `n` of `TEST_HANDS`
+#[cfg(test)]
+pub fn test_handles(n: usize) -> Vec<Handle> {
+    TEST_HANDS[..n].iter().map(|s| Handle::with_pool(s)).collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -338,6 +418,11 @@ mod tests {
             None, None, None, None, None, None, None, None, None, None, None, None, None, None,
         ];
         assert_eq!(color_result_to_index(&color_result), 4782968);
+        assert_eq!(index_to_color_result(4782968), color_result);
+
+        let color_result = parse_color_result("GYNNYGGNNNNNNY");
+        let index = color_result_to_index(&color_result);
+        assert_eq!(index_to_color_result(index), color_result);
     }
 
     #[test]
@@ -486,7 +571,8 @@ mod tests {
             pool: [false; 34],
             flags,
         };
-        assert!(!handle.match_context(&context));
+        assert!(handle.match_context(&context));
+        assert!(!handle.match_context(&Context::parse_context("")));
 
         let flags = 0b00000001;
         let context = Context::parse_context("");
@@ -497,4 +583,94 @@ mod tests {
         };
         assert!(!handle.match_context(&context));
     }
+
+    #[test]
+    fn test_check_merged_flags() {
+        // Yakuhai ton or nan koutsu depending on the decomposition
+        let flags = store::merge_flags(store::ANY_TON, store::ANY_NAN).unwrap();
+        assert_eq!(flags, store::ANY_TON | store::ANY_NAN);
+        assert!(Context::parse_context("e").check_flags(flags));
+        assert!(Context::parse_context("s").check_flags(flags));
+        assert!(!Context::parse_context("w").check_flags(flags));
+
+        // Pinfu with a ton jantou, or no yaku but tsumo
+        let flags = store::merge_flags(store::NOT_ANY_TON, store::MASK_FALSE_IF_RON).unwrap();
+        assert!(Context::parse_context("s").check_flags(flags));
+        assert!(Context::parse_context("t").check_flags(flags));
+        assert!(Context::parse_context("te").check_flags(flags));
+        assert!(!Context::parse_context("e").check_flags(flags));
+
+        // Wind clauses of the same polarity combine
+        let flags = store::merge_flags(store::NOT_ANY_TON, store::NOT_ANY_PEI).unwrap();
+        assert!(Context::parse_context("te").check_flags(flags));
+        assert!(Context::parse_context("tn").check_flags(flags));
+        assert!(!Context::parse_context("ten").check_flags(flags));
+
+        // A tsumo-only record does not depend on the winds
+        let flags = store::MASK_FALSE_IF_RON;
+        assert!(Context::parse_context("t").check_flags(flags));
+        assert!(Context::parse_context("teswn").check_flags(flags));
+        assert!(!Context::parse_context("eswn").check_flags(flags));
+
+        let flags = store::merge_flags(store::ANY_SHA, store::MASK_TRUE_ALWAYS).unwrap();
+        assert_eq!(flags, store::MASK_TRUE_ALWAYS);
+        assert!(store::merge_flags(store::ANY_TON, store::NOT_ANY_NAN).is_err());
+
+        // One record per hand, packed or not
+        let ton = Handle::from_string("123m345p345s111z22z");
+        let nan = Handle::from_string("123m345p345s222z11z");
+        let with = |handle: Handle, flags| Handle { flags, ..handle };
+        let mut records = vec![
+            with(nan, store::ANY_NAN),
+            with(ton, store::ANY_TON),
+            with(nan, store::NOT_ANY_NAN),
+        ];
+        store::merge_records(&mut records);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].flags, store::ANY_TON);
+        assert_eq!(records[1].flags, store::MASK_TRUE_ALWAYS);
+        let mut packed = vec![ton.to_u128(), ton.to_u128()];
+        store::merge_records(&mut packed);
+        assert_eq!(packed, vec![ton.to_u128()]);
+
+        let handle = Handle {
+            hand: [0; 14],
+            pool: [false; 34],
+            flags,
+        };
+        assert!(Handle::handle_to_string(&handle).ends_with(" _ALWAYS_"));
+        let handle = Handle {
+            flags: store::NOT_ANY_TON | store::MASK_FALSE_IF_RON,
+            ..handle
+        };
+        assert!(Handle::handle_to_string(&handle).ends_with(" _TSUMO__ 00010001"));
+    }
+
+    #[test]
+    fn test_migrate_flags() {
+        // Old NOT_ANY_TON to NOT_ANY_PEI
+        assert_eq!(store::migrate_flags(0b00000000), store::NOT_ANY_TON);
+        assert_eq!(store::migrate_flags(0b00010000), store::NOT_ANY_NAN);
+        assert_eq!(store::migrate_flags(0b00100000), store::NOT_ANY_SHA);
+        assert_eq!(store::migrate_flags(0b00110000), store::NOT_ANY_PEI);
+        // Unchanged clauses, and the current format read again
+        for flags in [
+            store::ANY_TON | store::ANY_SHA,
+            store::MASK_FALSE_IF_RON,
+            store::MASK_TRUE_ALWAYS,
+            store::NOT_ANY_NAN,
+            store::NOT_ANY_TON | store::NOT_ANY_PEI | store::MASK_FALSE_IF_RON,
+        ] {
+            assert_eq!(store::migrate_flags(flags), flags);
+        }
+
+        let old = Handle {
+            hand: [0; 14],
+            pool: [false; 34],
+            flags: 0b00110000,
+        };
+        let handle = Handle::from_u128(old.to_u128());
+        assert!(!handle.match_context(&Context::parse_context("n")));
+        assert!(handle.match_context(&Context::parse_context("e")));
+    }
 }
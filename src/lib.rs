@@ -1,6 +1,17 @@
+pub mod book;
+pub mod classes;
+pub mod evaluate;
 pub mod generator;
+pub mod growing_heap;
 pub mod handle;
+pub mod lookahead;
 pub mod mahd_fast;
 pub mod mahd_fast2;
+pub mod optimise;
+pub mod prior;
+pub mod search;
+pub mod solver;
+pub mod stats;
 pub mod store;
+pub mod tree;
 pub mod utils;
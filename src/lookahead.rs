@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+
+use super::handle::{Color, ColorResult, Handle};
+
+// Candidate sets up to this size are searched
+pub const MAX_CANDIDATES: usize = 256;
+
+// Guesses tried per candidate subset, ranked by entropy
+pub const TOP_K: usize = 8;
+
+const ALL_GREEN: ColorResult = [Color::Green; 14];
+
+// Expected number of guesses to find the answer among `hs`, each candidate
+// weighing its prior, counting the next guess. Subsets of `hs` are stored as
+// sorted indices.
+pub struct Lookahead<'a> {
+    hs: &'a Vec<Handle>,
+    weights: Vec<f64>,
+    pool: &'a Vec<Handle>,
+    top_k: usize,
+    hard: bool,
+    memo: HashMap<(Vec<u32>, usize), f64>,
+}
+
+impl<'a> Lookahead<'a> {
+    // `pool` holds the guesses to try besides the candidates themselves. In
+    // hard mode a guess must agree with every feedback on its path, which
+    // leaves the candidates of the subset it is tried on.
+    pub fn new(
+        hs: &'a Vec<Handle>,
+        pool: &'a Vec<Handle>,
+        top_k: usize,
+        hard: bool,
+        weights: &[f64],
+    ) -> Self {
+        Lookahead {
+            hs,
+            weights: weights.to_vec(),
+            pool,
+            top_k,
+            hard,
+            memo: HashMap::new(),
+        }
+    }
+
+    fn weight(&self, subset: &[u32]) -> f64 {
+        subset.iter().map(|&i| self.weights[i as usize]).sum()
+    }
+
+    // Lower bound: the next guess may win, at best the likeliest candidate,
+    // any later one separates the rest
+    fn estimate(&self, subset: &[u32]) -> f64 {
+        let max = subset
+            .iter()
+            .map(|&i| self.weights[i as usize])
+            .fold(0.0, f64::max);
+        2.0 - max / self.weight(subset)
+    }
+
+    fn split(&self, subset: &[u32], guess: &Handle) -> HashMap<ColorResult, Vec<u32>> {
+        let mut buckets: HashMap<ColorResult, Vec<u32>> = HashMap::new();
+        for &i in subset {
+            let result = self.hs[i as usize].get_color_result(guess);
+            buckets.entry(result).or_default().push(i);
+        }
+        buckets
+    }
+
+    fn guesses(&self, subset: &[u32]) -> Vec<Handle> {
+        let total = self.weight(subset);
+        // Candidates come first, so ties prefer a guess that may win
+        let mut guesses = subset
+            .iter()
+            .map(|&i| self.hs[i as usize])
+            .chain(self.pool.iter().copied().filter(|_| !self.hard))
+            .map(|guess| {
+                let entropy = self
+                    .split(subset, &guess)
+                    .values()
+                    .fold(0.0, |acc, bucket| {
+                        let p = self.weight(bucket) / total;
+                        acc - p * p.log2()
+                    });
+                (entropy, guess)
+            })
+            .collect::<Vec<_>>();
+        guesses.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+        guesses
+            .into_iter()
+            .take(self.top_k)
+            .map(|(_, guess)| guess)
+            .collect()
+    }
+
+    // `None` if the guess tells nothing about `subset`
+    fn cost(&mut self, subset: &[u32], guess: &Handle, depth: usize) -> Option<f64> {
+        let buckets = self.split(subset, guess);
+        if buckets.len() == 1 && !buckets.contains_key(&ALL_GREEN) {
+            return None;
+        }
+        let total = self.weight(subset);
+        let mut cost = 1.0;
+        for (result, bucket) in buckets {
+            if result != ALL_GREEN {
+                cost += self.weight(&bucket) / total * self.expected(&bucket, depth - 1);
+            }
+        }
+        Some(cost)
+    }
+
+    pub fn expected(&mut self, subset: &[u32], depth: usize) -> f64 {
+        let n = subset.len();
+        if n <= 1 {
+            return n as f64;
+        }
+        if depth == 0 {
+            return self.estimate(subset);
+        }
+        let key = (subset.to_vec(), depth);
+        if let Some(&cost) = self.memo.get(&key) {
+            return cost;
+        }
+        let mut best = f64::INFINITY;
+        for guess in self.guesses(subset) {
+            if let Some(cost) = self.cost(subset, &guess, depth) {
+                best = best.min(cost);
+            }
+        }
+        self.memo.insert(key, best);
+        best
+    }
+
+    // Searching `depth` guesses ahead, the rest estimated; `None` for depth 0
+    pub fn best_guess(&mut self, depth: usize) -> Option<(Handle, f64)> {
+        if depth == 0 {
+            return None;
+        }
+        let subset = (0..self.hs.len() as u32).collect::<Vec<_>>();
+        self.guesses(&subset)
+            .into_iter()
+            .filter_map(|guess| Some((guess, self.cost(&subset, &guess, depth)?)))
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::test_handles;
+
+    #[test]
+    fn test_best_guess() {
+        let hs = test_handles(3);
+        let pool = vec![];
+        let mut lookahead = Lookahead::new(&hs, &pool, TOP_K, false, &[1.0; 3]);
+
+        // Any candidate separates the other two
+        let (guess, expected) = lookahead.best_guess(2).unwrap();
+        assert!(hs.contains(&guess));
+        assert!((expected - 5.0 / 3.0).abs() < 1e-9);
+        assert!(lookahead.best_guess(0).is_none());
+
+        assert_eq!(lookahead.expected(&[1], 2), 1.0);
+        assert_eq!(lookahead.expected(&[0, 2], 2), 1.5);
+
+        // The likeliest candidate is guessed first
+        let mut lookahead = Lookahead::new(&hs, &pool, TOP_K, false, &[1.0, 1.0, 8.0]);
+        let (guess, expected) = lookahead.best_guess(2).unwrap();
+        assert_eq!(guess, hs[2]);
+        assert!((expected - 1.2).abs() < 1e-9);
+    }
+}
@@ -2,9 +2,9 @@ use std::cmp::Reverse;
 use std::collections::BinaryHeap;
 use std::collections::{HashMap, HashSet};
 use std::ops::Range;
-use std::u8;
 
-use super::handle::{Hand, Handle};
+use super::classes::Classes;
+use super::handle::{Color, ColorResult, Hand, Handle};
 
 const MAX_TILE: usize = 34;
 const MAX_POS: usize = 14;
@@ -14,101 +14,165 @@ const TILES: Range<usize> = 0..MAX_TILE;
 const POSES: Range<usize> = 0..MAX_POS;
 const PAIR_POSES: Range<usize> = 0..MAX_PAIR_POS;
 
-struct MetaMap {
-    gg: [[[u32; 34]; 34]; 13],
-    gy: [[[u32; 34]; 34]; 13],
-    yg: [[[u32; 34]; 34]; 13],
-    g: [[u32; 34]; 14],
-    yy: [[u32; 34]; 34],
-    y: [u32; 34],
-    total: u32,
+const SHARD_SIZE: usize = 1 << 14;
+
+type PairCounts = [[[f64; 34]; 34]; 13];
+
+// Large tables are boxed, as threads may not have room for a few of them on
+// their stack
+fn boxed<T: Clone, const N: usize>(value: T) -> Box<[T; N]> {
+    match vec![value; N].into_boxed_slice().try_into() {
+        Ok(boxed) => boxed,
+        Err(_) => unreachable!(),
+    }
+}
+
+// Counts over a set of handles, each weighing its prior, kept up to date as
+// handles come and go. Weights are given alongside the handles, see
+// `Prior::weights`
+#[derive(Clone, PartialEq)]
+pub struct MetaMap {
+    gg: Box<PairCounts>,
+    gy: Box<PairCounts>,
+    yg: Box<PairCounts>,
+    g: [[f64; 34]; 14],
+    yy: [[f64; 34]; 34],
+    y: [f64; 34],
+    total: f64,
 }
 
 impl MetaMap {
-    fn new() -> Self {
+    pub fn new() -> Self {
         MetaMap {
-            gg: [[[0; 34]; 34]; 13],
-            gy: [[[0; 34]; 34]; 13],
-            yg: [[[0; 34]; 34]; 13],
-            g: [[0; 34]; 14],
-            yy: [[0; 34]; 34],
-            y: [0; 34],
-            total: 0,
+            gg: boxed([[0.0; 34]; 34]),
+            gy: boxed([[0.0; 34]; 34]),
+            yg: boxed([[0.0; 34]; 34]),
+            g: [[0.0; 34]; 14],
+            yy: [[0.0; 34]; 34],
+            y: [0.0; 34],
+            total: 0.0,
         }
     }
 
-    fn register(mut self, handle: &Handle) -> Self {
-        for pos in PAIR_POSES {
-            self.gg[pos][handle.hand[pos] as usize][handle.hand[pos + 1] as usize] += 1;
-        }
+    // Shards of `SHARD_SIZE` handles at least are counted on separate threads
+    // and their maps summed
+    pub fn from_handles(inc: impl Fn() + Sync, hs: &Vec<Handle>, weights: &[f64]) -> Self {
+        let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let shard_size = hs.len().div_ceil(workers).max(SHARD_SIZE);
+        Self::from_shards(inc, hs, weights, shard_size)
+    }
+
+    fn from_shards(
+        inc: impl Fn() + Sync,
+        hs: &Vec<Handle>,
+        weights: &[f64],
+        shard_size: usize,
+    ) -> Self {
+        assert_eq!(hs.len(), weights.len());
+        let inc = &inc;
+        std::thread::scope(|scope| {
+            let shards = hs
+                .chunks(shard_size.max(1))
+                .zip(weights.chunks(shard_size.max(1)))
+                .map(|(shard, weights)| {
+                    scope.spawn(move || {
+                        let mut meta_map = MetaMap::new();
+                        for (handle, &weight) in shard.iter().zip(weights) {
+                            inc();
+                            meta_map.register(handle, weight);
+                        }
+                        meta_map
+                    })
+                })
+                .collect::<Vec<_>>();
+            shards
+                .into_iter()
+                .map(|shard| shard.join().unwrap())
+                .fold(MetaMap::new(), |acc, meta_map| acc + meta_map)
+        })
+    }
+
+    // Only the tiles in the pool of the handle are visited
+    fn update(&mut self, handle: &Handle, op: impl Fn(&mut f64)) {
+        let pool = TILES.filter(|&tile| handle.pool[tile]).collect::<Vec<_>>();
         for pos in PAIR_POSES {
-            for fst in TILES {
-                for snd in TILES {
-                    self.gy[pos][fst][snd] +=
-                        (handle.hand[pos] == fst as u8 && handle.pool[snd]) as u32;
-                    self.yg[pos][fst][snd] +=
-                        (handle.pool[fst] && handle.hand[pos + 1] == snd as u8) as u32;
-                }
+            let fst = handle.hand[pos] as usize;
+            let snd = handle.hand[pos + 1] as usize;
+            op(&mut self.gg[pos][fst][snd]);
+            for &tile in &pool {
+                op(&mut self.gy[pos][fst][tile]);
+                op(&mut self.yg[pos][tile][snd]);
             }
         }
         for pos in POSES {
-            self.g[pos][handle.hand[pos] as usize] += 1;
+            op(&mut self.g[pos][handle.hand[pos] as usize]);
         }
-        for fst in TILES {
-            // Note: This is a symmetric matrix. For the purpose of efficiency, we only
-            // store half of the matrix. Use `flip_yy` to complete the matrix before
-            // using it.
-            for snd in fst..MAX_TILE {
-                self.yy[fst][snd] += (handle.pool[fst] && handle.pool[snd]) as u32;
+        for &fst in &pool {
+            for &snd in &pool {
+                op(&mut self.yy[fst][snd]);
             }
+            op(&mut self.y[fst]);
         }
-        for tile in TILES {
-            self.y[tile] += handle.pool[tile] as u32;
-        }
-        self.total += 1;
-        self
+        op(&mut self.total);
     }
 
-    fn flip_yy(mut self) -> Self {
-        for fst in TILES {
-            for snd in (fst + 1)..MAX_TILE {
-                self.yy[snd][fst] = self.yy[fst][snd];
-            }
-        }
-        self
+    pub fn register(&mut self, handle: &Handle, weight: f64) {
+        self.update(handle, |count| *count += weight);
     }
-}
 
-impl std::ops::Add for MetaMap {
-    type Output = Self;
-    fn add(mut self, other: Self) -> Self {
+    // `handle` must have been registered with the same weight
+    pub fn unregister(&mut self, handle: &Handle, weight: f64) {
+        self.update(handle, |count| *count -= weight);
+    }
+
+    // Weight of the handles counted
+    pub fn total(&self) -> f64 {
+        self.total
+    }
+
+    fn zip(mut self, other: &Self, op: impl Fn(&mut f64, f64)) -> Self {
         for pos in PAIR_POSES {
             for fst in TILES {
                 for snd in TILES {
-                    self.gg[pos][fst][snd] += other.gg[pos][fst][snd];
-                    self.gy[pos][fst][snd] += other.gy[pos][fst][snd];
-                    self.yg[pos][fst][snd] += other.yg[pos][fst][snd];
+                    op(&mut self.gg[pos][fst][snd], other.gg[pos][fst][snd]);
+                    op(&mut self.gy[pos][fst][snd], other.gy[pos][fst][snd]);
+                    op(&mut self.yg[pos][fst][snd], other.yg[pos][fst][snd]);
                 }
             }
         }
         for pos in POSES {
             for tile in TILES {
-                self.g[pos][tile] += other.g[pos][tile];
+                op(&mut self.g[pos][tile], other.g[pos][tile]);
             }
         }
         for fst in TILES {
             for snd in TILES {
-                self.yy[fst][snd] += other.yy[fst][snd];
+                op(&mut self.yy[fst][snd], other.yy[fst][snd]);
             }
         }
         for tile in TILES {
-            self.y[tile] += other.y[tile];
+            op(&mut self.y[tile], other.y[tile]);
         }
-        self.total += other.total;
+        op(&mut self.total, other.total);
         self
     }
 }
 
+impl std::ops::Add for MetaMap {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        self.zip(&other, |count, other| *count += other)
+    }
+}
+
+// Counts of a subset of the handles may be taken off
+impl std::ops::Sub for MetaMap {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        self.zip(&other, |count, other| *count -= other)
+    }
+}
+
 enum PairColor {
     GG,
     GY,
@@ -124,12 +188,13 @@ enum PairColor {
 const VARIANT_COUNT_PAIR_COLOR: usize = 9;
 
 type CatagoryMap = (
-    [[[[u32; VARIANT_COUNT_PAIR_COLOR]; MAX_TILE]; MAX_TILE]; MAX_PAIR_POS],
-    u32,
+    Box<[[[[f64; VARIANT_COUNT_PAIR_COLOR]; MAX_TILE]; MAX_TILE]; MAX_PAIR_POS]>,
+    f64,
 );
 
-fn mk_catagory(meta_map: MetaMap) -> CatagoryMap {
-    let mut map = [[[[0; VARIANT_COUNT_PAIR_COLOR]; MAX_TILE]; MAX_TILE]; MAX_PAIR_POS];
+fn mk_catagory(meta_map: &MetaMap) -> CatagoryMap {
+    let mut map: Box<[_; MAX_PAIR_POS]> =
+        boxed([[[0.0; VARIANT_COUNT_PAIR_COLOR]; MAX_TILE]; MAX_TILE]);
 
     for pos in PAIR_POSES {
         for fst in TILES {
@@ -157,18 +222,27 @@ fn mk_catagory(meta_map: MetaMap) -> CatagoryMap {
     (map, meta_map.total)
 }
 
-type EntropyMap = [[[f64; MAX_TILE]; MAX_TILE]; MAX_PAIR_POS];
+type EntropyMap = Box<[[[f64; MAX_TILE]; MAX_TILE]; MAX_PAIR_POS]>;
 
 fn mk_entropy((catagory_map, total): CatagoryMap) -> EntropyMap {
-    let f_entropy = |x: &u32| -> f64 {
-        if *x > 0 {
-            let p = *x as f64 / total as f64;
+    // Weights taken off may leave rounding errors below zero
+    let f_entropy = |x: &f64| -> f64 {
+        if *x > 0.0 {
+            let p = *x / total;
             -p * p.log2()
         } else {
             0.0
         }
     };
-    catagory_map.map(|m| m.map(|m| m.map(|color| color.iter().map(f_entropy).sum::<f64>())))
+    let mut map: EntropyMap = boxed([[0.0; MAX_TILE]; MAX_TILE]);
+    for pos in PAIR_POSES {
+        for fst in TILES {
+            for snd in TILES {
+                map[pos][fst][snd] = catagory_map[pos][fst][snd].iter().map(f_entropy).sum();
+            }
+        }
+    }
+    map
 }
 
 fn find_entropy(entropy_map: &EntropyMap, handle: &Handle) -> f64 {
@@ -179,15 +253,71 @@ fn find_entropy(entropy_map: &EntropyMap, handle: &Handle) -> f64 {
     entropy
 }
 
-pub fn mahd_fast2_prepare(inc: impl Fn(), hs: &Vec<Handle>) -> EntropyMap {
-    let mut meta_map = MetaMap::new();
-    for handle in hs {
-        inc();
-        meta_map = meta_map.register(&handle);
+// Entropy of the color of a single position, the marginal of the pair colors
+type SingleEntropyMap = [[f64; MAX_TILE]; MAX_POS];
+
+fn mk_single_entropy(meta_map: &MetaMap) -> SingleEntropyMap {
+    let f_entropy = |x: f64| -> f64 {
+        if x > 0.0 {
+            let p = x / meta_map.total;
+            -p * p.log2()
+        } else {
+            0.0
+        }
+    };
+    let mut map = [[0.0; MAX_TILE]; MAX_POS];
+    for pos in POSES {
+        for tile in TILES {
+            let g = meta_map.g[pos][tile];
+            let y = meta_map.y[tile] - g;
+            let n = meta_map.total - meta_map.y[tile];
+            map[pos][tile] = f_entropy(g) + f_entropy(y) + f_entropy(n);
+        }
+    }
+    map
+}
+
+// Treats the colors as a Markov chain along the hand: the entropy of the pairs
+// counts every interior position twice, so its single entropy is taken off
+pub struct ChainEntropyMap {
+    pairs: EntropyMap,
+    singles: SingleEntropyMap,
+}
+
+fn find_chain_entropy(entropy_map: &ChainEntropyMap, handle: &Handle) -> f64 {
+    let mut entropy = find_entropy(&entropy_map.pairs, handle);
+    for pos in 1..MAX_PAIR_POS {
+        entropy -= entropy_map.singles[pos][handle.hand[pos] as usize];
+    }
+    entropy
+}
+
+pub fn mahd_chain_prepare(meta_map: &MetaMap) -> ChainEntropyMap {
+    ChainEntropyMap {
+        pairs: mk_entropy(mk_catagory(meta_map)),
+        singles: mk_single_entropy(meta_map),
     }
-    let meta_map = meta_map.flip_yy();
-    let catagory_map = mk_catagory(meta_map);
-    mk_entropy(catagory_map)
+}
+
+pub fn mahd_chain_entropy(
+    inc: impl Fn(),
+    hs_all: &Vec<Handle>,
+    entropy_map: &ChainEntropyMap,
+) -> Vec<HandleEntropy> {
+    hs_all
+        .iter()
+        .map(|handle| {
+            inc();
+            HandleEntropy {
+                hand: handle.hand.clone(),
+                entropy: find_chain_entropy(entropy_map, handle),
+            }
+        })
+        .collect::<Vec<_>>()
+}
+
+pub fn mahd_fast2_prepare(meta_map: &MetaMap) -> EntropyMap {
+    mk_entropy(mk_catagory(meta_map))
 }
 
 #[derive(Debug)]
@@ -256,37 +386,274 @@ pub fn mahd_fast2(inc: impl Fn(), hes: Vec<HandleEntropy>, size: usize) -> Vec<H
         .collect()
 }
 
+// Scores of adjacent position pairs in f32, with the chain correction folded
+// into the pair it follows, so either approximation is 13 lookups per hand
+pub struct ScoreTable(Box<[[[f32; MAX_TILE]; MAX_TILE]; MAX_PAIR_POS]>);
+
+impl ScoreTable {
+    fn new(score: impl Fn(usize, usize, usize) -> f64) -> Self {
+        let mut table: Box<[_; MAX_PAIR_POS]> = boxed([[0.0; MAX_TILE]; MAX_TILE]);
+        for pos in PAIR_POSES {
+            for fst in TILES {
+                for snd in TILES {
+                    table[pos][fst][snd] = score(pos, fst, snd) as f32;
+                }
+            }
+        }
+        ScoreTable(table)
+    }
+
+    pub fn from_pairs(entropy_map: &EntropyMap) -> Self {
+        Self::new(|pos, fst, snd| entropy_map[pos][fst][snd])
+    }
+
+    pub fn from_chain(entropy_map: &ChainEntropyMap) -> Self {
+        Self::new(|pos, fst, snd| {
+            let single = if pos + 1 < MAX_PAIR_POS {
+                entropy_map.singles[pos + 1][snd]
+            } else {
+                0.0
+            };
+            entropy_map.pairs[pos][fst][snd] - single
+        })
+    }
+
+    pub fn score(&self, hand: &Hand) -> f32 {
+        PAIR_POSES
+            .map(|pos| self.0[pos][hand[pos] as usize][hand[pos + 1] as usize])
+            .sum()
+    }
+
+    fn score_batch(&self, batch: &[Handle], scores: &mut [f32]) {
+        scores.fill(0.0);
+        for pos in PAIR_POSES {
+            let table = &self.0[pos];
+            for (score, handle) in scores.iter_mut().zip(batch) {
+                *score += table[handle.hand[pos] as usize][handle.hand[pos + 1] as usize];
+            }
+        }
+    }
+}
+
+pub const BATCH_SIZE: usize = 1024;
+
+// Best `size` of `hs_all` by the table, best first, one guess per class if
+// given. Every thread keeps its own top `size` over a shard, scoring a batch
+// of hands at a time. Progress is counted in batches,
+// `hs_all.len().div_ceil(BATCH_SIZE)` of them.
+pub fn mahd_fast2_stream(
+    inc: impl Fn() + Sync,
+    hs_all: &Vec<Handle>,
+    table: &ScoreTable,
+    size: usize,
+    classes: Option<&Classes>,
+) -> Vec<Handle> {
+    let key = |hand: &Hand| classes.map_or(*hand, |classes| classes.key(hand));
+    let key = &key;
+    let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+    // Whole batches per shard, so only the last batch of all is partial
+    let shard_size = hs_all
+        .len()
+        .div_ceil(workers)
+        .next_multiple_of(BATCH_SIZE)
+        .max(BATCH_SIZE);
+    let inc = &inc;
+    let mut hes = std::thread::scope(|scope| {
+        let shards = hs_all
+            .chunks(shard_size)
+            .map(|shard| {
+                scope.spawn(move || {
+                    let mut heap = BinaryHeap::with_capacity(size + 1);
+                    // Classes in the heap, only looked up for a hand good
+                    // enough to enter it
+                    let mut keys = HashSet::with_capacity(size + 1);
+                    let mut scores = [0.0; BATCH_SIZE];
+                    for batch in shard.chunks(BATCH_SIZE) {
+                        inc();
+                        table.score_batch(batch, &mut scores);
+                        for (&score, handle) in scores.iter().zip(batch) {
+                            let he = HandleEntropy {
+                                hand: handle.hand,
+                                entropy: score as f64,
+                            };
+                            let full = heap.len() >= size;
+                            if full && heap.peek().is_none_or(|min: &Reverse<_>| he <= min.0) {
+                                continue;
+                            }
+                            if !keys.insert(key(&he.hand)) {
+                                continue;
+                            }
+                            if full {
+                                let min = heap.pop().unwrap().0;
+                                keys.remove(&key(&min.hand));
+                            }
+                            heap.push(Reverse(he));
+                        }
+                    }
+                    heap
+                })
+            })
+            .collect::<Vec<_>>();
+        shards
+            .into_iter()
+            .flat_map(|shard| shard.join().unwrap())
+            .map(|he| he.0)
+            .collect::<Vec<_>>()
+    });
+    hes.sort_unstable_by(|a, b| b.cmp(a));
+    // A class may be kept by several threads
+    let mut seen = HashSet::new();
+    hes.retain(|he| seen.insert(key(&he.hand)));
+    hes.truncate(size);
+    hes.into_iter()
+        .map(|he| Handle {
+            hand: he.hand,
+            pool: [false; 34],
+            flags: 0,
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Approximation {
+    // Sum of the entropies of adjacent position pairs
+    Pairs,
+    // Pairs corrected for the positions they share, see `ChainEntropyMap`
+    Chain,
+}
+
+impl Approximation {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "pairs" => Some(Approximation::Pairs),
+            "chain" => Some(Approximation::Chain),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scoring {
+    // Maximise the expected information of the result
+    Entropy,
+    // Minimise the number of candidates left in the worst case
+    Minimax,
+    // Minimax, breaking ties by entropy
+    MinimaxEntropy,
+}
+
+impl Scoring {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "entropy" => Some(Scoring::Entropy),
+            "minimax" => Some(Scoring::Minimax),
+            "minimax-entropy" => Some(Scoring::MinimaxEntropy),
+            _ => None,
+        }
+    }
+
+    // `Greater` if `a` is the better guess, each bit of entropy being worth
+    // `win_weight` times the chance of winning right away
+    pub fn cmp(&self, a: &HandleScore, b: &HandleScore, win_weight: f64) -> std::cmp::Ordering {
+        let value = |score: &HandleScore| score.entropy + win_weight * score.win;
+        let by_entropy = value(a).partial_cmp(&value(b)).unwrap();
+        let by_max_bucket = b.max_bucket.cmp(&a.max_bucket);
+        let by_win = a.win.partial_cmp(&b.win).unwrap();
+        match self {
+            Scoring::Entropy => by_entropy,
+            Scoring::Minimax => by_max_bucket,
+            Scoring::MinimaxEntropy => by_max_bucket.then(by_entropy),
+        }
+        .then(by_win)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HandleScore {
+    pub hand: Hand,
+    pub entropy: f64,
+    pub max_bucket: u32,
+    // Chance that the guess is the answer
+    pub win: f64,
+}
+
+// Handles and their weight per result
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bucket {
+    pub count: u32,
+    pub weight: f64,
+}
+
+// `weights` are those of `handles`, in the same order
+pub fn partition(
+    handles: &Vec<Handle>,
+    guess: &Handle,
+    weights: &[f64],
+) -> HashMap<ColorResult, Bucket> {
+    assert_eq!(handles.len(), weights.len());
+    handles
+        .iter()
+        .zip(weights)
+        .fold(HashMap::new(), |mut acc, (key, &weight)| {
+            let result = key.get_color_result(guess);
+            let bucket = acc.entry(result).or_insert(Bucket {
+                count: 0,
+                weight: 0.0,
+            });
+            bucket.count += 1;
+            bucket.weight += weight;
+            acc
+        })
+}
+
+// The entropy and chance of winning follow the weights, the worst case the
+// number of handles
+pub fn score_partition(guess: &Handle, buckets: &HashMap<ColorResult, Bucket>) -> HandleScore {
+    let total = buckets.values().map(|bucket| bucket.weight).sum::<f64>();
+    HandleScore {
+        hand: guess.hand,
+        entropy: buckets.values().fold(0.0, |acc, bucket| {
+            let p = bucket.weight / total;
+            if p > 0.0 { acc - p * p.log2() } else { acc }
+        }),
+        max_bucket: buckets
+            .values()
+            .map(|bucket| bucket.count)
+            .max()
+            .unwrap_or(0),
+        win: buckets
+            .get(&[Color::Green; 14])
+            .map_or(0.0, |bucket| bucket.weight / total),
+    }
+}
+
 pub fn mahd_killer_prepare(
     inc: impl Fn(),
     handles: &Vec<Handle>,
     handles_all: &Vec<Handle>,
-) -> Vec<HandleEntropy> {
-    let total = handles.len() as u32;
+    weights: &[f64],
+) -> Vec<HandleScore> {
     handles_all
         .iter()
         .map(|guess| {
             inc();
-            HandleEntropy {
-                hand: guess.hand.clone(),
-                entropy: handles
-                    .iter()
-                    .fold(HashMap::new(), |mut acc, key| {
-                        let result = key.get_color_result(&guess);
-                        *acc.entry(result).or_insert(0) += 1;
-                        acc
-                    })
-                    .iter()
-                    .fold(0.0, |acc, (_, &count)| {
-                        let p = count as f64 / total as f64;
-                        acc - p * p.log2()
-                    }),
-            }
+            score_partition(guess, &partition(handles, guess, weights))
         })
         .collect()
 }
 
-pub fn mahd_killer(inc: impl Fn(), hes: Vec<HandleEntropy>, size: usize) -> Vec<HandleEntropy> {
-    top_n_biggest(inc, hes, size)
+// Best first
+pub fn mahd_killer(
+    inc: impl Fn(),
+    hes: Vec<HandleScore>,
+    size: usize,
+    scoring: Scoring,
+    win_weight: f64,
+) -> Vec<HandleScore> {
+    let mut hes = hes.into_iter().inspect(|_| inc()).collect::<Vec<_>>();
+    hes.sort_by(|a, b| scoring.cmp(b, a, win_weight));
+    hes.truncate(size);
+    hes
 }
 
 pub fn mahd_killer_inner(handles: &Vec<Handle>) -> Option<Handle> {
@@ -306,3 +673,143 @@ pub fn mahd_killer_inner(handles: &Vec<Handle>) -> Option<Handle> {
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::test_handles;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn test_scoring() {
+        let score = |entropy, max_bucket| HandleScore {
+            hand: [0; 14],
+            entropy,
+            max_bucket,
+            win: 0.0,
+        };
+        // Many small buckets but one large one, against even buckets
+        let spread = score(3.0, 40);
+        let even = score(2.5, 10);
+        let even_better = score(2.6, 10);
+
+        let best = |scoring: Scoring| {
+            mahd_killer(
+                || (),
+                vec![spread.clone(), even.clone(), even_better.clone()],
+                1,
+                scoring,
+                0.0,
+            )[0]
+            .entropy
+        };
+        assert_eq!(best(Scoring::Entropy), 3.0);
+        assert_eq!(best(Scoring::Minimax), 2.5);
+        assert_eq!(best(Scoring::MinimaxEntropy), 2.6);
+    }
+
+    #[test]
+    fn test_meta_map() {
+        let hs = test_handles(3);
+        let all = MetaMap::from_handles(|| (), &hs, &[1.0; 3]);
+        let first = MetaMap::from_handles(|| (), &hs[..1].to_vec(), &[1.0]);
+        let rest = MetaMap::from_handles(|| (), &hs[1..].to_vec(), &[1.0; 2]);
+
+        let mut meta_map = all.clone();
+        meta_map.unregister(&hs[0], 1.0);
+        assert!(meta_map == rest);
+        assert_eq!(meta_map.total(), 2.0);
+        assert!(all.clone() - first.clone() == rest);
+        assert!(first.clone() + rest == all);
+
+        let mut serial = MetaMap::new();
+        hs.iter().for_each(|handle| serial.register(handle, 1.0));
+        assert!(MetaMap::from_shards(|| (), &hs, &[1.0; 3], 1) == serial);
+
+        // A handle weighing twice counts as two
+        let weighted = MetaMap::from_handles(|| (), &hs, &[2.0, 1.0, 1.0]);
+        assert!(weighted == all + first);
+    }
+
+    #[test]
+    fn test_mahd_fast2_stream() {
+        let hs = test_handles(4);
+        let meta_map = MetaMap::from_handles(|| (), &hs, &[1.0; 4]);
+
+        let entropy_map = mahd_chain_prepare(&meta_map);
+        let table = ScoreTable::from_chain(&entropy_map);
+        let mut scores = [0.0; 4];
+        table.score_batch(&hs, &mut scores);
+        for (handle, score) in hs.iter().zip(scores) {
+            let entropy = find_chain_entropy(&entropy_map, handle);
+            assert!((entropy - score as f64).abs() < 1e-4);
+        }
+
+        let entropy_map = mahd_fast2_prepare(&meta_map);
+        let mut expected = mahd_fast2_entropy(|| (), &hs, &entropy_map);
+        expected.sort_by(|a, b| b.cmp(a));
+        let batches = AtomicUsize::new(0);
+        let inc = || {
+            batches.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        };
+        let top = mahd_fast2_stream(inc, &hs, &ScoreTable::from_pairs(&entropy_map), 2, None);
+        assert_eq!(batches.into_inner(), 1);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].hand, expected[0].hand);
+        assert_eq!(top[1].hand, expected[1].hand);
+
+        // Guesses of a class are ranked once
+        let candidates = hs[..2].to_vec();
+        let meta_map = MetaMap::from_handles(|| (), &candidates, &[1.0; 2]);
+        let table = ScoreTable::from_pairs(&mahd_fast2_prepare(&meta_map));
+        let guesses = ["123456789m11p123s", "123456789m11p223s"]
+            .iter()
+            .map(|s| Handle::from_string(s))
+            .collect::<Vec<_>>();
+        let classes = Classes::new(&candidates);
+        let top = mahd_fast2_stream(|| (), &guesses, &table, 2, Some(&classes));
+        assert_eq!(top.len(), 1);
+        assert_eq!(mahd_fast2_stream(|| (), &guesses, &table, 2, None).len(), 2);
+    }
+
+    #[test]
+    fn test_score_partition() {
+        let hs = test_handles(3);
+        let score = score_partition(&hs[0], &partition(&hs, &hs[0], &[1.0; 3]));
+        assert_eq!(score.max_bucket, 1);
+        assert!((score.entropy - 3f64.log2()).abs() < 1e-9);
+        assert!((score.win - 1.0 / 3.0).abs() < 1e-9);
+
+        let score = score_partition(&hs[0], &partition(&hs, &hs[0], &[2.0, 1.0, 1.0]));
+        assert_eq!(score.max_bucket, 1);
+        assert!((score.entropy - 1.5).abs() < 1e-9);
+        assert!((score.win - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_win_weight() {
+        let score = |entropy, win| HandleScore {
+            hand: [0; 14],
+            entropy,
+            max_bucket: 10,
+            win,
+        };
+        let outside = score(3.0, 0.0);
+        let candidate = score(2.9, 0.2);
+
+        let best = |scoring: Scoring, win_weight| {
+            mahd_killer(
+                || (),
+                vec![outside.clone(), candidate.clone()],
+                1,
+                scoring,
+                win_weight,
+            )[0]
+            .win
+        };
+        assert_eq!(best(Scoring::Entropy, 0.0), 0.0);
+        assert_eq!(best(Scoring::Entropy, 1.0), 0.2);
+        // Ties prefer a candidate
+        assert_eq!(best(Scoring::Minimax, 0.0), 0.2);
+    }
+}
@@ -1,85 +1,260 @@
 // use mahc::calc::get_yaku_han;
 // use mahc::hand;
+use clap::{Arg, ArgAction, ArgMatches, Command};
 use indicatif::{ProgressBar, ProgressStyle};
-use mahjong_handle_solver::mahd_fast2::mahd_killer_inner;
+use mahjong_handle_solver::book::Book;
+use mahjong_handle_solver::evaluate;
+use mahjong_handle_solver::generator::{Detail, Unit};
+use mahjong_handle_solver::mahd_fast2::{Approximation, Scoring, partition, score_partition};
+use mahjong_handle_solver::prior::{self, Prior};
+use mahjong_handle_solver::search::Rules;
+use mahjong_handle_solver::solver::{self, Candidates, Policy, Pool};
+use mahjong_handle_solver::stats::Stats;
 use mahjong_handle_solver::utils::STYLE;
-use mahjong_handle_solver::{
-    generator::Generator,
-    handle::Handle,
-    mahd_fast2::{
-        mahd_fast2, mahd_fast2_entropy, mahd_fast2_prepare, mahd_killer, mahd_killer_prepare,
-    },
-};
+use mahjong_handle_solver::{call_with_progress, generator::Generator, handle::Handle};
 use mahjong_handle_solver::{handle, store};
-use std::collections::HashMap;
-use std::env;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::OpenOptions;
 use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, SeekFrom};
 use std::mem::size_of;
+use std::time::Duration;
 use std::{fs::File, io::Write};
 
-fn _generate_data(pb: ProgressBar) {
+// "data" holds every hand, "data_te" only the hands valid in context "te" and
+// "data_-" only the hands valid in the empty context
+fn context_path(name: &str, context: Option<&handle::Context>) -> String {
+    match context.map(|context| context.key()) {
+        Some(key) if key.is_empty() => format!("{}_-", name),
+        Some(key) => format!("{}_{}", name, key),
+        None => name.to_string(),
+    }
+}
+
+// Progress of `generate_data`: the units done so far are in the raw file
+#[derive(Debug, PartialEq, Eq)]
+struct Checkpoint {
+    // Fingerprint of `Unit::all`, whose indices the counts refer to
+    layout: u64,
+    // Records of each unit, in unit order
+    counts: Vec<u64>,
+    checksum: u64,
+}
+
+impl Checkpoint {
+    fn new() -> Self {
+        Checkpoint {
+            layout: Self::layout(),
+            counts: vec![],
+            checksum: store::CHECKSUM_INIT,
+        }
+    }
+
+    fn layout() -> u64 {
+        let units = format!("{:?}", Unit::all());
+        store::checksum(store::CHECKSUM_INIT, units.as_bytes())
+    }
+
+    fn units(&self) -> usize {
+        self.counts.len()
+    }
+
+    fn records(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    fn path(raw: &str) -> String {
+        raw.to_string() + ".ckpt"
+    }
+
+    // A checkpoint of other units or that does not add up is ignored,
+    // generating from scratch
+    fn load(raw: &str) -> Option<Self> {
+        let s = std::fs::read_to_string(Self::path(raw)).ok()?;
+        let mut lines = s.lines();
+        let mut fields = lines.next()?.split_whitespace().map(|f| f.parse::<u64>());
+        let mut field = || fields.next()?.ok();
+        let (units, records, checksum, layout) = (field()?, field()?, field()?, field()?);
+        let counts = lines
+            .next()
+            .unwrap_or("")
+            .split_whitespace()
+            .map(|count| count.parse::<u64>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        let checkpoint = Checkpoint {
+            layout,
+            counts,
+            checksum,
+        };
+        (layout == Self::layout()
+            && checkpoint.units() as u64 == units
+            && checkpoint.records() == records)
+            .then_some(checkpoint)
+    }
+
+    // Written to a temporary file first, so an interruption keeps the old one
+    fn save(&self, raw: &str) {
+        let path = Self::path(raw);
+        let tmp = path.clone() + ".tmp";
+        let counts = self.counts.iter().map(|count| count.to_string());
+        std::fs::write(
+            &tmp,
+            format!(
+                "{} {} {} {}\n{}\n",
+                self.units(),
+                self.records(),
+                self.checksum,
+                self.layout,
+                counts.collect::<Vec<_>>().join(" ")
+            ),
+        )
+        .unwrap();
+        std::fs::rename(tmp, path).unwrap();
+    }
+}
+
+// Generates the raw records into "raw" or "raw_<ctx>", one per decomposition,
+// then merges them into the data and index files the solver reads
+fn generate_data(pb: ProgressBar, context: Option<handle::Context>) {
     pb.set_style(ProgressStyle::with_template(STYLE).unwrap());
+    let raw = context_path("raw", context.as_ref());
+    let mut checkpoint = Checkpoint::load(&raw).unwrap_or(Checkpoint::new());
+    if checkpoint.units() > 0 {
+        println!("Resuming from unit {}...", checkpoint.units());
+    }
+    let mut generator = Generator::resume(checkpoint.units(), context);
+    let units = std::iter::from_fn(|| generator.next_unit());
+    write_raw(&pb, &raw, &mut checkpoint, units);
+    verify_raw(&raw, &checkpoint, context);
+
+    let records = call_with_progress!("Refining data", checkpoint.records(), refine_data, &raw);
+    match context {
+        Some(context) => write_index(
+            records,
+            &load_opener(&context),
+            &context_path("index", Some(&context)),
+            &context_path("data", Some(&context)),
+        ),
+        None => {
+            write_data_all(&records);
+            write_index(records, &Handle::best_1st(), "index", "data");
+        }
+    }
+}
+
+// Appends the units to the raw file after the records of `checkpoint`,
+// dropping those of a unit that was interrupted
+fn write_raw(
+    pb: &ProgressBar,
+    raw: &str,
+    checkpoint: &mut Checkpoint,
+    units: impl Iterator<Item = (usize, Vec<Handle>)>,
+) {
     const TASK: &str = "Generating cache file... ";
-    println!("Cache file not found, generating...");
-    let mut file = File::create("data").unwrap();
-    Generator::new().for_each(|handle| {
-        file.write_all(&handle.to_u128().to_be_bytes()).unwrap();
-        pb.set_message(format!("{} {}", Handle::handle_to_string(&handle), TASK));
+    let all = Unit::all();
+    pb.set_length(all.len() as u64);
+    pb.set_position(checkpoint.units() as u64);
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(raw)
+        .unwrap();
+    file.set_len(checkpoint.records() * size_of::<u128>() as u64)
+        .unwrap();
+    file.seek(SeekFrom::End(0)).unwrap();
+    let mut file = BufWriter::new(file);
+
+    for (unit, handles) in units {
+        assert_eq!(unit, checkpoint.units(), "units out of order");
+        for handle in &handles {
+            let bytes = handle.to_u128().to_be_bytes();
+            file.write_all(&bytes).unwrap();
+            checkpoint.checksum = store::checksum(checkpoint.checksum, &bytes);
+        }
+        file.flush().unwrap();
+        file.get_ref().sync_data().unwrap();
+        checkpoint.counts.push(handles.len() as u64);
+        checkpoint.save(raw);
+        pb.set_message(format!("{:?} {}", all[unit], TASK));
         pb.inc(1);
-    });
+    }
     pb.finish_with_message(TASK.to_string() + "done");
 }
 
-fn _refine_data(pb: ProgressBar, mut infile: File) {
-    pb.set_style(ProgressStyle::with_template(STYLE).unwrap());
-    const TASK: &str = "Refining cache file... ";
-    let mut handtable: HashMap<[u8; 14], u128> = HashMap::new();
+// Units generated again to check the raw file against
+const VERIFY_UNITS: usize = 16;
+
+// Checks the raw file against its checkpoint, and a sample of its units
+// against the generator
+fn verify_raw(raw: &str, checkpoint: &Checkpoint, context: Option<handle::Context>) {
+    let file = File::open(raw).unwrap();
+    let records = file.metadata().unwrap().len() / size_of::<u128>() as u64;
+    assert_eq!(records, checkpoint.records(), "record count mismatch");
+    let mut file = BufReader::new(file);
     let mut buffer = [0u8; 16];
-    loop {
-        match infile.read(&mut buffer) {
-            Ok(16) => {
-                pb.inc(1);
-                // if rand::thread_rng().gen_range(0..TOTAL) < SAMPLE_SIZE {
-                let handle = Handle::from_u128(u128::from_be_bytes(buffer));
-                let hand = handle.hand;
-                if let Some(&raw) = handtable.get(&hand) {
-                    let flags = handle.flags;
-                    let flags_old = Handle::from_u128(raw).flags;
-                    pb.set_message(format!(
-                        "{} {}/{}",
-                        Handle::hand_to_string(&hand),
-                        flags,
-                        flags_old
-                    ));
-                    if flags & store::MASK_TRUE_ALWAYS != 0
-                        || (flags & store::MASK_FALSE_IF_RON == 0
-                            && flags_old & store::MASK_FALSE_IF_RON != 0)
-                    {
-                        handtable.insert(hand, handle.to_u128());
-                    }
-                } else {
-                    handtable.insert(hand, handle.to_u128());
-                }
-                // Handle::handle_to_string(&handle);
-                // pb.set_message(format!("{} {}", Handle::handle_to_string(&handle), TASK));
-                // pb.set_message(format!(" {}",  TASK));
-                //     tx.send(handle).unwrap();
-                // }
-                // tx.send(handle).unwrap();
-            }
-            Ok(0) => break,
-            _ => panic!("read error"),
+    let mut checksum = store::CHECKSUM_INIT;
+    for _ in 0..records {
+        file.read_exact(&mut buffer).unwrap();
+        checksum = store::checksum(checksum, &buffer);
+    }
+    assert_eq!(checksum, checkpoint.checksum, "checksum mismatch");
+
+    let units = Unit::all();
+    let step = checkpoint.units().div_ceil(VERIFY_UNITS).max(1);
+    for unit in (0..checkpoint.units()).step_by(step) {
+        let offset = checkpoint.counts[..unit].iter().sum::<u64>();
+        let expected = units[unit].run::<Handle>(context);
+        assert_eq!(
+            expected.len() as u64,
+            checkpoint.counts[unit],
+            "unit {:?} record count mismatch",
+            units[unit]
+        );
+        file.seek(SeekFrom::Start(offset * 16)).unwrap();
+        for handle in expected {
+            file.read_exact(&mut buffer).unwrap();
+            assert_eq!(
+                buffer,
+                handle.to_u128().to_be_bytes(),
+                "unit {:?} record mismatch",
+                units[unit]
+            );
         }
     }
-    let mut outfile = File::create("data2").unwrap();
-    for (_, &raw) in handtable.iter() {
-        outfile.write_all(&raw.to_be_bytes()).unwrap();
+    println!("Verified {} records, checksum {:016x}", records, checksum);
+}
+
+// One record per hand, the flags of its decompositions merged. Records stay
+// packed, as the raw file may hold tens of millions of them.
+fn refine_data(inc: impl Fn(), raw: &str) -> Vec<u128> {
+    let mut file = BufReader::new(File::open(raw).unwrap());
+    let mut records = vec![];
+    let mut buffer = [0u8; 16];
+    while file.read_exact(&mut buffer).is_ok() {
+        inc();
+        records.push(u128::from_be_bytes(buffer));
+    }
+    store::merge_records(&mut records);
+    records
+}
+
+// Hands and flags only, the guess pool of `load_data_all`
+fn write_data_all(records: &[u128]) {
+    let mut file = BufWriter::new(File::create("data_all").unwrap());
+    for record in records {
+        let handle = Handle::from_u128(*record);
+        file.write_all(&handle.hand).unwrap();
+        file.write_all(&[handle.flags]).unwrap();
     }
-    pb.finish_with_message(TASK.to_string() + &"done");
+    file.flush().unwrap();
 }
 
-fn _load_data(inc: impl Fn()) -> Vec<Handle> {
-    let mut file = File::open("data").unwrap();
+fn load_data(inc: impl Fn(), path: &str) -> Vec<Handle> {
+    let mut file = BufReader::new(File::open(path).unwrap());
     let mut buffer = [0u8; 16];
     let mut hs = vec![];
     loop {
@@ -95,22 +270,282 @@ fn _load_data(inc: impl Fn()) -> Vec<Handle> {
     hs
 }
 
-macro_rules! call_with_progress {
-    ($name:expr, $total:expr, $func:expr, $($args:expr),* $(,)?) => {{
-        let pb = ProgressBar::new($total as u64);
-        pb.set_style(ProgressStyle::with_template(STYLE).unwrap());
-        pb.set_message($name);
-        let result = $func(&|| pb.inc(1), $($args,)*);
-        pb.finish_with_message($name.to_string() + &" done");
-        result
-    }};
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let value = value.parse::<f64>().map_err(|e| e.to_string())?;
+    let seconds = match unit {
+        "ms" => value / 1000.0,
+        "s" => value,
+        "m" => value * 60.0,
+        _ => return Err(format!("unknown unit {}", unit)),
+    };
+    Ok(Duration::from_secs_f64(seconds))
 }
 
-fn get_context() -> handle::Context {
-    let args: Vec<String> = env::args().collect();
-    let default = "".to_string();
-    let arg = args.get(1).unwrap_or(&default);
-    handle::Context::parse_context(&arg)
+fn policy_args() -> Vec<Arg> {
+    vec![
+        Arg::new("scoring")
+            .long("scoring")
+            .value_parser(["entropy", "minimax", "minimax-entropy"])
+            .default_value("entropy"),
+        Arg::new("approximation")
+            .long("approximation")
+            .help("Pre-ranking of the guesses before their exact partition")
+            .value_parser(["pairs", "chain"])
+            .default_value("pairs"),
+        Arg::new("win-weight")
+            .long("win-weight")
+            .help("Bits of entropy traded for a sure win")
+            .value_parser(clap::value_parser!(f64))
+            .default_value("0"),
+        Arg::new("think")
+            .long("think")
+            .help("Score guesses exactly for this long, e.g. 500ms, 5s or 1m")
+            .value_parser(parse_duration),
+        Arg::new("hard")
+            .long("hard")
+            .help("Only guess hands consistent with every result so far")
+            .action(ArgAction::SetTrue),
+        Arg::new("lookahead")
+            .long("lookahead")
+            .help("Guesses searched ahead for small candidate sets, 0 to disable")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("2"),
+        Arg::new("export-tree")
+            .long("export-tree")
+            .help("Write the optimal decision tree of small candidate sets as JSON"),
+        Arg::new("rules")
+            .long("rules")
+            .help("Guesses the game accepts: winning hands, any tiles in order, or any tiles")
+            .value_parser(["winning", "tiles", "any"])
+            .default_value("winning"),
+        Arg::new("optimise")
+            .long("optimise")
+            .help("Guesses grown a move at a time from the best one, 0 to disable")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("0"),
+        Arg::new("prior")
+            .long("prior")
+            .help("JSON weights of the answers, such as the fitted prior, uniform if omitted"),
+    ]
+}
+
+fn cli() -> Command {
+    Command::new("mahjong-handle-solver")
+        .args_conflicts_with_subcommands(true)
+        .arg(Arg::new("context").default_value(""))
+        .args(policy_args())
+        .arg(
+            Arg::new("no-book")
+                .long("no-book")
+                .help("Ignore the opening book")
+                .action(ArgAction::SetTrue),
+        )
+        .subcommand(
+            Command::new("generate")
+                .about("Generate the cache file, resuming from the last checkpoint")
+                .arg(
+                    Arg::new("context")
+                        .long("context")
+                        .help("Only keep the hands valid in this context"),
+                ),
+        )
+        .subcommand(
+            Command::new("index")
+                .about("Sort the hands of a context by the result of its opener")
+                .arg(Arg::new("context").default_value("")),
+        )
+        .subcommand(
+            Command::new("book")
+                .about("Build the opening book, resuming from the guesses already in it")
+                .arg(Arg::new("context").default_value(""))
+                .arg(
+                    Arg::new("third")
+                        .long("third")
+                        .help("Also book third guesses after seconds leaving this many hands")
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .args(policy_args()),
+        )
+        .subcommand(
+            Command::new("opener")
+                .about("Rank openers by exact partition over every answer, per context")
+                .arg(
+                    Arg::new("contexts")
+                        .num_args(0..)
+                        .help("Contexts to rank for, all of them if omitted"),
+                )
+                .arg(
+                    Arg::new("candidates")
+                        .long("candidates")
+                        .help("Openers kept from the approximate ranking")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("1000"),
+                )
+                .arg(
+                    Arg::new("top")
+                        .long("top")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::new("save")
+                        .long("save")
+                        .help("Save the best opener of each context and index its answers by it")
+                        .action(ArgAction::SetTrue),
+                )
+                .args(policy_args()),
+        )
+        .subcommand(
+            Command::new("evaluate")
+                .about("Compare the approximate and exact entropy over sampled index buckets")
+                .arg(Arg::new("context").default_value(""))
+                .arg(
+                    Arg::new("samples")
+                        .long("samples")
+                        .help("Index buckets to sample as candidate sets")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("20"),
+                )
+                .arg(
+                    Arg::new("guesses")
+                        .long("guesses")
+                        .help("Guesses sampled from the pool of the context per bucket")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("10000"),
+                )
+                .arg(
+                    Arg::new("approximation")
+                        .long("approximation")
+                        .value_parser(["pairs", "chain"])
+                        .default_value("pairs"),
+                )
+                .arg(
+                    Arg::new("seed")
+                        .long("seed")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::new("prior")
+                        .long("prior")
+                        .help("JSON weights of the answers, uniform if omitted"),
+                ),
+        )
+        .subcommand(
+            Command::new("prior")
+                .about("Fit the prior of the answers to past ones, saved next to the cache")
+                .arg(
+                    Arg::new("history")
+                        .required(true)
+                        .help("Past answers, one hand and its context per line"),
+                ),
+        )
+        .subcommand(
+            Command::new("stats")
+                .about("Count hands per class, from the generator or a cache file")
+                .arg(Arg::new("file").help("Cache file, generates the hands if omitted"))
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_parser(["table", "csv", "json"])
+                        .default_value("table"),
+                ),
+        )
+}
+
+fn get_context(matches: &ArgMatches) -> handle::Context {
+    handle::Context::parse_context(matches.get_one::<String>("context").unwrap())
+}
+
+fn get_policy(matches: &ArgMatches) -> Policy {
+    Policy {
+        scoring: Scoring::parse(matches.get_one::<String>("scoring").unwrap()).unwrap(),
+        approximation: Approximation::parse(matches.get_one::<String>("approximation").unwrap())
+            .unwrap(),
+        win_weight: *matches.get_one::<f64>("win-weight").unwrap(),
+        think: matches.get_one::<Duration>("think").copied(),
+        hard: matches.get_flag("hard"),
+        lookahead: *matches.get_one::<usize>("lookahead").unwrap(),
+        export_tree: matches.get_one::<String>("export-tree").cloned(),
+        rules: Rules::parse(matches.get_one::<String>("rules").unwrap()).unwrap(),
+        optimise: *matches.get_one::<usize>("optimise").unwrap(),
+        prior: load_prior(matches),
+    }
+}
+
+fn load_prior(matches: &ArgMatches) -> Prior {
+    match matches.get_one::<String>("prior") {
+        Some(path) => {
+            let json = std::fs::read_to_string(path)
+                .ok()
+                .and_then(|s| serde_json::from_str(&s).ok())
+                .unwrap_or_else(|| panic!("{} should be a JSON file", path));
+            Prior::from_json(&json).unwrap_or_else(|| {
+                panic!(
+                    "{} should hold a model or a table of positive weights",
+                    path
+                )
+            })
+        }
+        None => Prior::Uniform,
+    }
+}
+
+// Fits the prior to the answers of `history`, matched to the cache of their context
+fn fit_prior(history: &str) {
+    let mut contexts = HashMap::<String, (handle::Context, Vec<Handle>)>::new();
+    for line in std::fs::read_to_string(history).unwrap().lines() {
+        let mut words = line.split_whitespace();
+        let Some(hand) = words.next() else {
+            continue;
+        };
+        let context = handle::Context::parse_context(words.next().unwrap_or(""));
+        contexts
+            .entry(context.key())
+            .or_insert_with(|| (context, vec![]))
+            .1
+            .push(Handle::from_string(hand));
+    }
+    let mut sets = vec![];
+    for (context, past) in contexts.into_values() {
+        let mut candidates = load_answers(&context);
+        // A hand found by several decompositions is one candidate, valid
+        // wherever any of them is. Only stale caches need it, "data" and
+        // "data_<ctx>" holding one record per hand already.
+        store::merge_records(&mut candidates);
+        let by_hand = candidates
+            .iter()
+            .map(|handle| (handle.hand, *handle))
+            .collect::<HashMap<_, _>>();
+        // The flags of an answer come from the cache
+        let answers = past
+            .iter()
+            .filter_map(|handle| {
+                let answer = by_hand.get(&handle.hand).copied();
+                if answer.is_none() {
+                    println!(
+                        "{} is no answer in context {:?}, skipped",
+                        Handle::hand_to_string(&handle.hand),
+                        context.key()
+                    );
+                }
+                answer
+            })
+            .collect::<Vec<_>>();
+        sets.push((candidates, answers));
+    }
+    let answers = sets.iter().map(|(_, answers)| answers.len()).sum::<usize>();
+    let model = call_with_progress!("Fitting prior", prior::FIT_STEPS, prior::fit, &sets);
+    for (name, weight) in prior::FEATURES.iter().zip(model.weights) {
+        println!("{:>10} {:+.3}", name, weight);
+    }
+    let path = context_path("prior", None);
+    std::fs::write(&path, Prior::Model(model).to_json().to_string()).unwrap();
+    println!("Fitted to {} answers, saved to {}", answers, path);
 }
 
 fn get_result() -> handle::ColorResult {
@@ -119,8 +554,8 @@ fn get_result() -> handle::ColorResult {
     handle::parse_color_result(&buffer)
 }
 
-fn load_index_file(result: &handle::ColorResult) -> (u32, u32) {
-    let mut file = File::open("index").unwrap();
+fn load_index_file(path: &str, result: &handle::ColorResult) -> (u32, u32) {
+    let mut file = File::open(path).unwrap();
     let color_index = handle::color_result_to_index(result);
     file.seek(std::io::SeekFrom::Start((color_index - 1) as u64 * 4))
         .unwrap();
@@ -132,8 +567,22 @@ fn load_index_file(result: &handle::ColorResult) -> (u32, u32) {
     (index, index_end)
 }
 
-fn load_data_with_index(inc: impl Fn(), index: u32, index_end: u32) -> Vec<Handle> {
-    let mut file = File::open("data").unwrap();
+// The whole index of `layout`, and the color result indices of its non-empty
+// buckets, each spanning `index[i - 1]..index[i]` as in `load_index_file`
+fn load_buckets(layout: &Layout) -> (Vec<u32>, Vec<usize>) {
+    let index = std::fs::read(&layout.index)
+        .unwrap()
+        .chunks_exact(4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    let buckets = (1..index.len())
+        .filter(|&i| index[i] > index[i - 1])
+        .collect::<Vec<_>>();
+    (index, buckets)
+}
+
+fn load_data_with_index(inc: impl Fn(), path: &str, index: u32, index_end: u32) -> Vec<Handle> {
+    let mut file = File::open(path).unwrap();
     file.seek(std::io::SeekFrom::Start(index as u64 * 16))
         .unwrap();
     let mut buffer = [0u8; 16]; // u128
@@ -157,7 +606,7 @@ fn load_data_all(inc: impl Fn()) -> Vec<Handle> {
                 hs.push(Handle {
                     hand: buffer[0..14].try_into().unwrap(),
                     pool: [false; 34],
-                    flags: buffer[14],
+                    flags: store::migrate_flags(buffer[14]),
                 })
             }
             Ok(0) => break,
@@ -176,26 +625,366 @@ fn filter_context(inc: impl Fn(), hs: Vec<Handle>, context: &handle::Context) ->
         .collect::<Vec<Handle>>()
 }
 
+// A cache file holds one record per hand, the generator one per decomposition
+fn collect_stats(inc: impl Fn(), file: Option<&String>) -> Stats {
+    match file {
+        Some(file) => {
+            let mut stats = Stats::new();
+            let mut file = BufReader::new(File::open(file).unwrap());
+            let mut buffer = [0u8; 16];
+            while file.read_exact(&mut buffer).is_ok() {
+                inc();
+                stats.register(&Handle::from_u128(u128::from_be_bytes(buffer)));
+            }
+            stats
+        }
+        None => Stats::from_details(Generator::<Detail>::spawn(0, None).inspect(|_| inc())),
+    }
+}
+
+fn print_stats(matches: &ArgMatches) {
+    let file = matches.get_one::<String>("file");
+    let len = file.map_or(0, |file| {
+        File::open(file).unwrap().metadata().unwrap().len() / size_of::<u128>() as u64
+    });
+    let stats = call_with_progress!("Counting hands", len, collect_stats, file);
+    let stdout = std::io::stdout();
+    match matches.get_one::<String>("format").unwrap().as_str() {
+        "csv" => stats.write_csv(stdout).unwrap(),
+        "json" => println!(
+            "{}",
+            serde_json::to_string_pretty(&stats.to_json()).unwrap()
+        ),
+        _ => stats.write_table(stdout).unwrap(),
+    }
+}
+
+// The answers of a context, from its own cache file when generated
+fn load_answers(context: &handle::Context) -> Vec<Handle> {
+    let path = context_path("data", Some(context));
+    let path = if File::open(&path).is_ok() {
+        path
+    } else {
+        "data".to_string()
+    };
+    let len = File::open(&path).unwrap().metadata().unwrap().len() / size_of::<u128>() as u64;
+    let hs = call_with_progress!("Loading data", len, load_data, &path);
+    call_with_progress!("Filtering context", hs.len(), filter_context, hs, context)
+}
+
+fn search_openers(matches: &ArgMatches) {
+    let contexts = match matches.get_many::<String>("contexts") {
+        Some(contexts) => contexts
+            .map(|context| handle::Context::parse_context(context))
+            .collect(),
+        None => handle::Context::all(),
+    };
+    let n_candidate = *matches.get_one::<usize>("candidates").unwrap();
+    let top = *matches.get_one::<usize>("top").unwrap();
+    let policy = get_policy(matches);
+    for context in contexts {
+        let hs = load_answers(&context);
+        let openers = solver::best_openers(&hs, n_candidate, top, &policy);
+        let current = load_opener(&context);
+        let current = score_partition(
+            &current,
+            &partition(&hs, &current, &policy.prior.weights(&hs)),
+        );
+        let key = match context.key() {
+            key if key.is_empty() => "-".to_string(),
+            key => key,
+        };
+        println!("[{}] {} answers", key, hs.len());
+        println!(
+            "current {} {} {}",
+            Handle::hand_to_string(&current.hand),
+            current.entropy,
+            current.max_bucket
+        );
+        for (rank, opener) in openers.iter().enumerate() {
+            println!(
+                "{:>7} {} {} {}",
+                rank + 1,
+                Handle::hand_to_string(&opener.hand),
+                opener.entropy,
+                opener.max_bucket
+            );
+        }
+        // The current opener may have been missed by the approximation
+        let best = match openers.first() {
+            Some(opener)
+                if policy
+                    .scoring
+                    .cmp(opener, &current, policy.win_weight)
+                    .is_gt() =>
+            {
+                opener
+            }
+            _ => &current,
+        };
+        // The index is only valid for its opener, so both are saved together
+        if matches.get_flag("save") {
+            let opener = Handle {
+                hand: best.hand,
+                pool: [false; 34],
+                flags: 0,
+            };
+            write_index(
+                hs.iter().map(Handle::to_u128).collect(),
+                &opener,
+                &context_path("index", Some(&context)),
+                &context_path("data", Some(&context)),
+            );
+            let path = context_path("opener", Some(&context));
+            std::fs::write(&path, Handle::hand_to_string(&opener.hand) + "\n").unwrap();
+        }
+    }
+}
+
+// Up to `size` distinct guesses of the pool of the context, drawn from
+// "data_all", and the size of that pool estimated from the draws
+fn sample_guesses(rng: &mut StdRng, context: &handle::Context, size: usize) -> (Vec<Handle>, u64) {
+    let mut file = File::open("data_all").unwrap();
+    let len = file.metadata().unwrap().len() / 15;
+    let mut drawn = HashSet::new();
+    let mut buffer = [0u8; 15];
+    let mut guesses = vec![];
+    while guesses.len() < size && (drawn.len() as u64) < len {
+        let index = rng.gen_range(0..len);
+        if !drawn.insert(index) {
+            continue;
+        }
+        file.seek(SeekFrom::Start(index * 15)).unwrap();
+        file.read_exact(&mut buffer).unwrap();
+        let handle = Handle {
+            hand: buffer[0..14].try_into().unwrap(),
+            pool: [false; 34],
+            flags: store::migrate_flags(buffer[14]),
+        };
+        if handle.match_context(context) {
+            guesses.push(handle);
+        }
+    }
+    let pool = len * guesses.len() as u64 / drawn.len().max(1) as u64;
+    (guesses, pool)
+}
+
+// Buckets drawn per sample at most, before giving up on finding candidate
+// sets of 2 hands or more
+const MAX_DRAWS: usize = 100;
+
+fn run_evaluation(matches: &ArgMatches) {
+    let context = get_context(matches);
+    let samples = *matches.get_one::<usize>("samples").unwrap();
+    let n_guess = *matches.get_one::<usize>("guesses").unwrap();
+    let approximation =
+        Approximation::parse(matches.get_one::<String>("approximation").unwrap()).unwrap();
+    let prior = load_prior(matches);
+    let mut rng = StdRng::seed_from_u64(*matches.get_one::<u64>("seed").unwrap());
+
+    let layout = Layout::new(&context);
+    let (index, buckets) = load_buckets(&layout);
+    if buckets.is_empty() {
+        println!("No answer in {}", layout.data);
+        return;
+    }
+
+    println!(
+        "{:>8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>14}",
+        "size", "spearman", "top1", "top10", "top100", "needed", "budget"
+    );
+    let mut evaluations = vec![];
+    let mut draws = 0;
+    while evaluations.len() < samples {
+        if draws == samples * MAX_DRAWS {
+            println!("Too few buckets of 2 hands or more, stopped");
+            break;
+        }
+        draws += 1;
+        let i = buckets[rng.gen_range(0..buckets.len())];
+        let hs = load_data_with_index(|| (), &layout.data, index[i - 1], index[i]);
+        let hs = filter_context(|| (), hs, &context);
+        if hs.len() < 2 {
+            continue;
+        }
+        let (guesses, pool) = sample_guesses(&mut rng, &context, n_guess);
+        if guesses.is_empty() {
+            println!("No guess in data_all");
+            return;
+        }
+        let evaluation = evaluate::evaluate(&hs, &guesses, approximation, &prior.weights(&hs));
+        // `n_killer_candidate` times the candidates, over the whole pool
+        let budget = evaluation.needed as u64 * pool / guesses.len() as u64 * hs.len() as u64;
+        println!(
+            "{:>8} {:>8.4} {:>8.3} {:>8.3} {:>8.3} {:>8} {:>14}",
+            evaluation.size,
+            evaluation.correlation,
+            evaluation.recall[0],
+            evaluation.recall[1],
+            evaluation.recall[2],
+            evaluation.needed,
+            budget
+        );
+        evaluations.push((evaluation, budget));
+    }
+
+    if evaluations.is_empty() {
+        return;
+    }
+    let mean = |f: &dyn Fn(&evaluate::Evaluation) -> f64| {
+        evaluations.iter().map(|(e, _)| f(e)).sum::<f64>() / evaluations.len() as f64
+    };
+    println!(
+        "{:>8} {:>8.4} {:>8.3} {:>8.3} {:>8.3} {:>8} {:>14}",
+        "mean",
+        mean(&|e| e.correlation),
+        mean(&|e| e.recall[0]),
+        mean(&|e| e.recall[1]),
+        mean(&|e| e.recall[2]),
+        "",
+        ""
+    );
+    let mut budgets = evaluations
+        .iter()
+        .map(|(_, budget)| *budget)
+        .collect::<Vec<_>>();
+    budgets.sort();
+    println!(
+        "budget: median {}, p90 {}, max {} (currently {})",
+        budgets[budgets.len() / 2],
+        budgets[budgets.len() * 9 / 10],
+        budgets[budgets.len() - 1],
+        solver::KILLER_BUDGET
+    );
+}
+
 fn main() {
-    let context = get_context();
+    let matches = cli().get_matches();
+    match matches.subcommand() {
+        Some(("generate", matches)) => generate_data(
+            ProgressBar::new(0),
+            matches
+                .get_one::<String>("context")
+                .map(|context| handle::Context::parse_context(context)),
+        ),
+        Some(("book", matches)) => build_book(
+            get_context(matches),
+            get_policy(matches),
+            matches.get_one::<usize>("third").copied(),
+        ),
+        Some(("index", matches)) => build_index(get_context(matches)),
+        Some(("opener", matches)) => search_openers(matches),
+        Some(("evaluate", matches)) => run_evaluation(matches),
+        Some(("stats", matches)) => print_stats(matches),
+        Some(("prior", matches)) => fit_prior(matches.get_one::<String>("history").unwrap()),
+        _ => {
+            let context = get_context(&matches);
+            let policy = get_policy(&matches);
+            let header = book_header(&context, &policy);
+            let book = if matches.get_flag("no-book") {
+                Book::new(header)
+            } else {
+                load_book(&context, header)
+            };
+            solve(context, policy, book)
+        }
+    }
+}
 
-    // Provide best 1st guess
-    let mut guess = Handle::best_1st();
-    println!("[1] guess: {}", Handle::handle_to_string(&guess));
+fn load_opener(context: &handle::Context) -> Handle {
+    std::fs::read_to_string(context_path("opener", Some(context)))
+        .map(|s| Handle::from_string(s.trim()))
+        .unwrap_or(Handle::best_1st())
+}
 
-    // Collect the color results from std input
-    print!("[1] result: ");
-    std::io::stdout().flush().unwrap();
-    let result = get_result();
+// Files a session starts from: the opener, the index of its results and the
+// data sorted by them. Contexts with an index of their own, see `build_index`,
+// use their own opener, otherwise "index" and "data" are built for
+// `Handle::best_1st` over every hand.
+struct Layout {
+    opener: Handle,
+    index: String,
+    data: String,
+}
 
-    // Load index file
-    let (index, index_end) = load_index_file(&result);
+impl Layout {
+    fn new(context: &handle::Context) -> Self {
+        let index = context_path("index", Some(context));
+        if File::open(&index).is_ok() {
+            Layout {
+                opener: load_opener(context),
+                index,
+                data: context_path("data", Some(context)),
+            }
+        } else {
+            Layout {
+                opener: Handle::best_1st(),
+                index: "index".to_string(),
+                data: "data".to_string(),
+            }
+        }
+    }
+}
 
-    // Generate from the cache file
-    let hs = load_data_with_index(|| (), index, index_end);
-    let mut hs = filter_context(|| (), hs, &context);
+// Sorts the answers of the context by the result of its opener
+fn build_index(context: handle::Context) {
+    let opener = load_opener(&context);
+    println!("Opener: {}", Handle::hand_to_string(&opener.hand));
+    let records = load_answers(&context).iter().map(Handle::to_u128).collect();
+    write_index(
+        records,
+        &opener,
+        &context_path("index", Some(&context)),
+        &context_path("data", Some(&context)),
+    );
+}
+
+// Writes the records sorted by the result of `opener`, and the end of each
+// result's records in the index
+fn write_index(records: Vec<u128>, opener: &Handle, index: &str, data: &str) {
+    const TOTAL_COLOR_RESULT: usize = 4782969;
+    let len = records.len();
+    let mut records = call_with_progress!("Indexing data", len, |inc: &dyn Fn() -> ()| {
+        records
+            .into_iter()
+            .map(|record| {
+                inc();
+                let result = Handle::from_u128(record).get_color_result(opener);
+                (handle::color_result_to_index(&result), record)
+            })
+            .collect::<Vec<_>>()
+    },);
+    records.sort_unstable_by_key(|(index, record)| (*index, store::get_hand(record)));
+
+    let mut counts = vec![0u32; TOTAL_COLOR_RESULT];
+    for (index, _) in &records {
+        counts[*index as usize] += 1;
+    }
+    let tmp = index.to_string() + ".tmp";
+    let mut file = BufWriter::new(File::create(&tmp).unwrap());
+    let mut end = 0;
+    for count in counts {
+        end += count;
+        file.write_all(&end.to_be_bytes()).unwrap();
+    }
+    file.flush().unwrap();
+    std::fs::rename(tmp, index).unwrap();
+
+    let tmp = data.to_string() + ".tmp";
+    let mut file = BufWriter::new(File::create(&tmp).unwrap());
+    for (_, record) in &records {
+        file.write_all(&record.to_be_bytes()).unwrap();
+    }
+    file.flush().unwrap();
+    std::fs::rename(tmp, data).unwrap();
+    println!("Indexed {} records into {}", records.len(), data);
+}
 
-    // loading all data
+// Every hand valid in the context, or none in hard mode
+fn load_guess_pool(context: &handle::Context, policy: &Policy) -> Pool {
+    if policy.hard {
+        return Pool::new(vec![]);
+    }
     let len = File::open("data").unwrap().metadata().unwrap().len() / size_of::<u128>() as u64;
     let hs_all = call_with_progress!(
         "Loading all data",
@@ -203,75 +992,145 @@ fn main() {
         load_data_all,
     );
 
-    let hs_all = call_with_progress!(
+    Pool::new(call_with_progress!(
         "Filtering all context",
         hs_all.len(),
         filter_context,
         hs_all,
-        &context
-    );
+        context
+    ))
+}
 
-    let mut round = 1;
+// The policy and the opener the guesses of a book are found with
+fn book_header(context: &handle::Context, policy: &Policy) -> serde_json::Value {
+    serde_json::json!({
+        "opener": Handle::hand_to_string(&Layout::new(context).opener.hand),
+        "policy": policy.to_json(),
+    })
+}
 
-    loop {
-        guess = {
-            let guess_opt = if hs.len() < 1000 {
-                // finding inner killer
-                mahd_killer_inner(&hs)
-            } else {
-                None
-            };
+// An empty book if none was built with `header`
+fn load_book(context: &handle::Context, header: serde_json::Value) -> Book {
+    let path = context_path("book", Some(context));
+    let Ok(bytes) = std::fs::read(&path) else {
+        return Book::new(header);
+    };
+    match Book::from_bytes(&bytes) {
+        Some(book) if book.header() == &header => book,
+        _ => {
+            println!("{} was built for another policy or opener, ignored", path);
+            Book::new(header)
+        }
+    }
+}
 
-            if let Some(guess) = guess_opt {
+// Candidates left by each result of `guess`
+fn split(hs: Vec<Handle>, guess: &Handle) -> BTreeMap<u32, Vec<Handle>> {
+    let mut buckets: BTreeMap<u32, Vec<Handle>> = BTreeMap::new();
+    for handle in hs {
+        let index = handle::color_result_to_index(&handle.get_color_result(guess));
+        buckets.entry(index).or_default().push(handle);
+    }
+    buckets
+}
+
+fn build_book(context: handle::Context, policy: Policy, third: Option<usize>) {
+    let path = context_path("book", Some(&context));
+    let mut book = load_book(&context, book_header(&context, &policy));
+    if !book.is_empty() {
+        println!("Resuming with {} guesses...", book.len());
+    }
+    // Rewritten whole, without a partial record or a stale book
+    let tmp = path.clone() + ".tmp";
+    std::fs::write(&tmp, book.to_bytes()).unwrap();
+    std::fs::rename(tmp, &path).unwrap();
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+
+    let layout = Layout::new(&context);
+    let (index, buckets) = load_buckets(&layout);
+    let hs_all = load_guess_pool(&context, &policy);
+
+    for (n, &i) in buckets.iter().enumerate() {
+        let first = handle::index_to_color_result(i as u32);
+        let hs = load_data_with_index(|| (), &layout.data, index[i - 1], index[i]);
+        let hs = filter_context(|| (), hs, &context);
+        println!(
+            "[{}/{}] {} {} hands",
+            n + 1,
+            buckets.len(),
+            handle::color_result_to_string(&first),
+            hs.len()
+        );
+        if hs.is_empty() {
+            continue;
+        }
+        let guess = match book.get(&first, None) {
+            Some(guess) => guess,
+            None => {
+                let candidates = Candidates::new(hs.clone(), &policy.prior);
+                let guess = solver::best_guess(&candidates, &hs_all, &policy);
+                file.write_all(&book.insert(&first, None, &guess)).unwrap();
                 guess
-            } else {
-                // finding best 2nd guess
-                let entropy_map =
-                    call_with_progress!("Preparing Entropy Map", hs.len(), mahd_fast2_prepare, &hs);
-
-                let n_killer_candidate = 10000000 / hs.len();
-
-                let hes = call_with_progress!(
-                    "Finding Best Guess Entropy",
-                    hs_all.len(),
-                    mahd_fast2_entropy,
-                    &hs_all,
-                    &entropy_map,
-                );
+            }
+        };
+        if third.is_none_or(|third| hs.len() < third) {
+            continue;
+        }
+        for (index, hs) in split(hs, &guess) {
+            let second = handle::index_to_color_result(index);
+            // A single hand left is guessed right away
+            if hs.len() < 2 || book.contains(&first, Some(&second)) {
+                continue;
+            }
+            let guess = solver::best_guess(&Candidates::new(hs, &policy.prior), &hs_all, &policy);
+            file.write_all(&book.insert(&first, Some(&second), &guess))
+                .unwrap();
+        }
+    }
+}
 
-                let result = call_with_progress!(
-                    "Finding Best Guess",
-                    hes.len(),
-                    mahd_fast2,
-                    hes,
-                    n_killer_candidate,
-                );
+// Candidates listed with their chance of being the answer, once this few
+const SHOW_CANDIDATES: usize = 10;
 
-                // finding killer
-                let hes = call_with_progress!(
-                    "Finding Killer Prepare",
-                    n_killer_candidate,
-                    mahd_killer_prepare,
-                    &hs,
-                    &result
-                );
+fn solve(context: handle::Context, policy: Policy, book: Book) {
+    // Provide best 1st guess
+    let layout = Layout::new(&context);
+    let mut guess = layout.opener;
+    println!("[1] guess: {}", Handle::handle_to_string(&guess));
 
-                let result =
-                    call_with_progress!("Finding Killer", hes.len(), mahd_killer, hes, 10,);
+    // Collect the color results from std input
+    print!("[1] result: ");
+    std::io::stdout().flush().unwrap();
+    let first = get_result();
 
-                for handle in &result {
-                    println!(
-                        "{} {}",
-                        Handle::hand_to_string(&handle.hand),
-                        handle.entropy
-                    );
-                }
+    // Load index file
+    let (index, index_end) = load_index_file(&layout.index, &first);
 
-                Handle {
-                    hand: result.iter().max().unwrap().hand,
-                    pool: [false; 34],
-                    flags: 0,
-                }
+    // Generate from the cache file
+    let hs = load_data_with_index(|| (), &layout.data, index, index_end);
+    let hs = filter_context(|| (), hs, &context);
+    let mut candidates = Candidates::new(hs, &policy.prior);
+
+    // Only loaded once the book runs out
+    let mut hs_all = None;
+    let mut second = None;
+
+    let mut round = 1;
+
+    loop {
+        let booked = match round {
+            1 => book.get(&first, None),
+            2 => second.and_then(|second| book.get(&first, Some(&second))),
+            _ => None,
+        };
+        guess = match booked {
+            Some(guess) => {
+                println!("book: {}", Handle::hand_to_string(&guess.hand));
+                guess
+            }
+            None => {
+                let hs_all = hs_all.get_or_insert_with(|| load_guess_pool(&context, &policy));
+                solver::best_guess(&candidates, hs_all, &policy)
             }
         };
 
@@ -282,20 +1141,29 @@ fn main() {
         print!("[{}] result: ", round);
         std::io::stdout().flush().unwrap();
         let result = get_result();
+        if round == 2 && booked.is_some() {
+            second = Some(result);
+        }
 
-        hs = call_with_progress!("Filtering color result", hs.len(), |inc: &dyn Fn()
-            -> ()| {
-            inc();
-            hs.into_iter()
-                .filter(|handle| handle.match_color_result(&guess, &result))
-                .collect::<Vec<Handle>>()
-        },);
+        candidates = candidates.filter(&guess, &result);
+        println!("{} candidates left", candidates.hs.len());
+        if candidates.hs.len() <= SHOW_CANDIDATES {
+            let probabilities = candidates.probabilities();
+            for (handle, probability) in candidates.hs.iter().zip(probabilities) {
+                println!(
+                    "  {} {:.3}",
+                    Handle::hand_to_string(&handle.hand),
+                    probability
+                );
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashSet;
     use std::fs::File;
 
     // #[test]
@@ -415,6 +1283,70 @@ mod tests {
         writer.flush().unwrap();
     }
 
+    #[test]
+    fn test_checkpoint() {
+        let raw = std::env::temp_dir().join("mahjong-handle-solver-checkpoint");
+        let raw = raw.to_str().unwrap();
+        let checkpoint = Checkpoint {
+            counts: vec![3, 0, 5],
+            checksum: 42,
+            ..Checkpoint::new()
+        };
+        checkpoint.save(raw);
+        assert_eq!(Checkpoint::load(raw), Some(checkpoint));
+
+        // Counts that do not add up, the older format, and other units
+        let layout = Checkpoint::layout();
+        std::fs::write(Checkpoint::path(raw), format!("3 9 42 {}\n3 0 5\n", layout)).unwrap();
+        assert_eq!(Checkpoint::load(raw), None);
+        std::fs::write(Checkpoint::path(raw), "3 8 42\n").unwrap();
+        assert_eq!(Checkpoint::load(raw), None);
+        std::fs::write(Checkpoint::path(raw), "3 8 42 7\n3 0 5\n").unwrap();
+        assert_eq!(Checkpoint::load(raw), None);
+        std::fs::remove_file(Checkpoint::path(raw)).unwrap();
+    }
+
+    #[test]
+    fn test_resume() {
+        let units = Unit::all();
+        let run = |unit: usize| (unit, units[unit].run::<Handle>(None));
+        let dir = std::env::temp_dir();
+        let raw = dir.join("mahjong-handle-solver-resumed");
+        let raw = raw.to_str().unwrap();
+        let _ = std::fs::remove_file(raw);
+        let pb = ProgressBar::hidden();
+
+        let mut checkpoint = Checkpoint::new();
+        write_raw(&pb, raw, &mut checkpoint, (0..2).map(run));
+        // A unit interrupted halfway, dropped on resume
+        let mut file = OpenOptions::new().append(true).open(raw).unwrap();
+        file.write_all(&[0xff; 24]).unwrap();
+        drop(file);
+        let mut checkpoint = Checkpoint::load(raw).unwrap();
+        assert_eq!(checkpoint.units(), 2);
+        write_raw(&pb, raw, &mut checkpoint, (2..4).map(run));
+        verify_raw(raw, &checkpoint, None);
+
+        let whole = dir.join("mahjong-handle-solver-whole");
+        let whole = whole.to_str().unwrap();
+        let _ = std::fs::remove_file(whole);
+        let mut expected = Checkpoint::new();
+        write_raw(&pb, whole, &mut expected, (0..4).map(run));
+        assert_eq!(checkpoint, expected);
+        assert_eq!(std::fs::read(raw).unwrap(), std::fs::read(whole).unwrap());
+
+        // One record per hand, whatever its decompositions
+        let records = refine_data(|| (), raw);
+        let hands = records.iter().map(store::get_hand).collect::<HashSet<_>>();
+        assert_eq!(hands.len(), records.len());
+        assert!(records.len() < expected.records() as usize);
+
+        for path in [raw, whole] {
+            std::fs::remove_file(path).unwrap();
+            std::fs::remove_file(Checkpoint::path(path)).unwrap();
+        }
+    }
+
     #[test]
     fn test_handle_1() {
         use super::handle::Color::*;
@@ -0,0 +1,220 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+use super::growing_heap::{Counters, Growable, GrowingHeap, Lru};
+use super::handle::{Hand, Handle};
+use super::mahd_fast2::{HandleScore, partition, score_partition};
+use super::search::Rules;
+
+// Guesses waiting to be grown, the worst ones being dropped beyond
+const BEAM: usize = 1024;
+// Hands remembered so as not to be scored again
+const VISITED: usize = 1 << 16;
+
+// The candidates and what may be guessed at them
+struct Space<'a> {
+    hs: &'a Vec<Handle>,
+    // Hands of the guess pool, the only ones accepted under winning rules
+    pool: &'a HashSet<Hand>,
+    rules: Rules,
+    // Those of `hs`, in the same order
+    weights: &'a [f64],
+}
+
+impl Space<'_> {
+    fn accepts(&self, hand: &Hand) -> bool {
+        if self.rules.winning {
+            self.pool.contains(hand)
+        } else {
+            self.rules.is_valid(hand)
+        }
+    }
+
+    fn score(&self, hand: Hand) -> HandleScore {
+        let guess = Handle {
+            hand,
+            pool: [false; 34],
+            flags: 0,
+        };
+        score_partition(&guess, &partition(self.hs, &guess, self.weights))
+    }
+}
+
+// Hands one move away: another tile at a position, the winning tile included,
+// or a run or triplet moved to neighbouring numbers or another suit
+fn moves(hand: &Hand) -> Vec<Hand> {
+    let mut result = vec![];
+    for pos in 0..14 {
+        for tile in 0..34 {
+            if tile != hand[pos] {
+                let mut next = *hand;
+                next[pos] = tile;
+                result.push(next);
+            }
+        }
+    }
+    for pos in 0..11 {
+        let group = [hand[pos], hand[pos + 1], hand[pos + 2]];
+        let run = group[0] < 27 && group[1] == group[0] + 1 && group[2] == group[0] + 2;
+        let triplet = group[0] == group[1] && group[1] == group[2];
+        if !run && !triplet {
+            continue;
+        }
+        for shift in [-1, 1, -9, 9, -18, 18] {
+            let moved = group.map(|tile| tile as i32 + shift);
+            if moved.iter().any(|&tile| !(0..34).contains(&tile))
+                || moved.iter().any(|&tile| tile / 9 != moved[0] / 9)
+            {
+                continue;
+            }
+            let mut next = *hand;
+            for (i, tile) in moved.into_iter().enumerate() {
+                next[pos + i] = tile as u8;
+            }
+            result.push(next);
+        }
+    }
+    result
+}
+
+// A guess ranked by its exact partition entropy
+#[derive(Clone)]
+struct Node<'a> {
+    score: HandleScore,
+    space: &'a Space<'a>,
+}
+
+impl PartialEq for Node<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.score.hand == other.score.hand
+    }
+}
+
+impl Eq for Node<'_> {}
+
+impl PartialOrd for Node<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Node<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .entropy
+            .total_cmp(&other.score.entropy)
+            .then_with(|| self.score.hand.cmp(&other.score.hand))
+    }
+}
+
+impl Growable for Node<'_> {
+    type Key = Hand;
+
+    fn key(&self) -> Hand {
+        self.score.hand
+    }
+
+    fn grow(&self) -> impl Iterator<Item = Hand> {
+        let mut hands = moves(&self.score.hand)
+            .into_iter()
+            .map(|mut hand| {
+                self.space.rules.normalize(&mut hand);
+                hand
+            })
+            .filter(|hand| *hand != self.score.hand && self.space.accepts(hand))
+            .collect::<Vec<_>>();
+        hands.sort_unstable();
+        hands.dedup();
+        hands.into_iter()
+    }
+
+    // Only a hand not visited yet is scored
+    fn build(&self, hand: Hand) -> Self {
+        Node {
+            score: self.space.score(hand),
+            space: self.space,
+        }
+    }
+}
+
+// Expands the best guess found so far `steps` times from `seed`, returning
+// the guesses expanded, best first at every step, and the search counters.
+// `pool` is only looked up under winning rules.
+pub fn optimise(
+    inc: impl Fn(),
+    hs: &Vec<Handle>,
+    weights: &[f64],
+    pool: &HashSet<Hand>,
+    seed: &Handle,
+    rules: &Rules,
+    steps: usize,
+) -> (Vec<HandleScore>, Counters) {
+    let space = Space {
+        hs,
+        pool,
+        rules: *rules,
+        weights,
+    };
+    let seed = Node {
+        score: space.score(seed.hand),
+        space: &space,
+    };
+    let mut heap = GrowingHeap::bounded(seed, Lru::new(VISITED), BEAM);
+    let scores = heap
+        .by_ref()
+        .take(steps)
+        .inspect(|_| inc())
+        .map(|node| node.score)
+        .collect();
+    (scores, *heap.counters())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::test_handles;
+
+    #[test]
+    fn test_optimise() {
+        let hs = test_handles(4);
+
+        let moved = moves(&hs[2].hand);
+        assert!(moved.contains(&Handle::from_string("234456789m11p123s").hand));
+        assert!(moved.contains(&Handle::from_string("123456789m11p12s3p").hand));
+
+        // No other hand of the pool is a move away
+        let pool = hs.iter().map(|handle| handle.hand).collect();
+        let seed = hs[3];
+        let uniform = [1.0; 4];
+        let (result, counters) = optimise(|| (), &hs, &uniform, &pool, &seed, &Rules::new(), 3);
+        assert_eq!(result.len(), 1);
+        assert_eq!(counters.pushed, 1);
+        assert_eq!(result[0].hand, seed.hand);
+
+        let rules = Rules::parse("tiles").unwrap();
+        let (result, counters) = optimise(|| (), &hs, &uniform, &pool, &seed, &rules, 3);
+        assert_eq!(counters.expansions, 3);
+        assert_eq!(result.len(), 3);
+        // A single move separates every candidate
+        assert!(result[0].entropy < 1.0);
+        assert!((result[1].entropy - 2.0).abs() < 1e-9);
+
+        // Guesses of equal entropy are told apart by their hands in the frontier
+        let space = Space {
+            hs: &hs,
+            pool: &pool,
+            rules,
+            weights: &uniform,
+        };
+        let node = |hand| Node {
+            score: HandleScore {
+                hand,
+                entropy: 1.0,
+                max_bucket: 1,
+                win: 0.0,
+            },
+            space: &space,
+        };
+        assert_ne!(node(hs[0].hand).cmp(&node(hs[1].hand)), Ordering::Equal);
+    }
+}
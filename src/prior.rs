@@ -0,0 +1,470 @@
+use std::collections::HashMap;
+
+use super::generator::Shape;
+use super::handle::{Hand, Handle};
+use super::stats::shape_of;
+use super::store;
+
+// Hand features weighed by a `Model`
+pub const FEATURES: [&str; 13] = [
+    // Honor tiles
+    "honors",
+    // Ones and nines
+    "terminals",
+    // Distinct tiles held twice or more
+    "pairs",
+    // Distinct tiles held three times or more
+    "triplets",
+    // Number suits held
+    "suits",
+    // No honor nor terminal at all
+    "simples",
+    // Only a yaku if won by tsumo
+    "tsumo",
+    // Won on a pair or a triplet in every decomposition, never on a run
+    "pair_wait",
+    // Dragon triplets, a yakuhai in every context
+    "yakuhai",
+    // Seven pairs
+    "chiitoi",
+    // A single number suit together with honors
+    "honitsu",
+    // A single number suit and no honor
+    "chinitsu",
+    // Four triplets and a pair
+    "toitoi",
+];
+
+// Most Newton steps in `fit`
+pub const FIT_STEPS: usize = 100;
+// The fit stops once the gradient is this small
+const TOLERANCE: f64 = 1e-6;
+// Pulls the weights towards uniform, so that a short history does not diverge
+const REGULARIZATION: f64 = 0.01;
+
+type Features = [f64; FEATURES.len()];
+// The negated Hessian of the objective of `fit`
+type Curvature = [[f64; FEATURES.len()]; FEATURES.len()];
+
+// Whether the tiles left form `sets` sets and a pair, unless the pair is
+// already taken
+fn complete(copies: &mut [u32; 34], sets: usize, pair: bool) -> bool {
+    // The lowest tile left starts a pair, a triplet or a run
+    let Some(tile) = copies.iter().position(|&n| n > 0) else {
+        return sets == 0 && pair;
+    };
+    let n = copies[tile];
+    let run = tile < 27 && tile % 9 < 7 && copies[tile + 1] > 0 && copies[tile + 2] > 0;
+    let mut take = |group: &[usize], sets: usize, pair: bool| {
+        group.iter().for_each(|&tile| copies[tile] -= 1);
+        let found = complete(copies, sets, pair);
+        group.iter().for_each(|&tile| copies[tile] += 1);
+        found
+    };
+    !pair && n >= 2 && take(&[tile; 2], sets, true)
+        || sets > 0 && n >= 3 && take(&[tile; 3], sets - 1, pair)
+        || sets > 0 && run && take(&[tile, tile + 1, tile + 2], sets - 1, pair)
+}
+
+// The winning tile completes a pair or a triplet in every standard
+// decomposition, a run in none, as `generator::Wait` would tell
+fn pair_wait(copies: &[u32; 34], winning_tile: usize) -> bool {
+    if winning_tile >= 27 {
+        return true;
+    }
+    let first = winning_tile - winning_tile % 9;
+    (winning_tile.saturating_sub(2).max(first)..=winning_tile.min(first + 6)).all(|start| {
+        let mut copies = *copies;
+        let run = start..start + 3;
+        if run.clone().any(|tile| copies[tile] == 0) {
+            return true;
+        }
+        run.for_each(|tile| copies[tile] -= 1);
+        !complete(&mut copies, 3, false)
+    })
+}
+
+// Cheap enough to weigh every candidate: the wait comes from a search of
+// this hand's sets only, not from the generator
+pub fn features(handle: &Handle) -> Features {
+    let mut copies = [0u32; 34];
+    for &tile in &handle.hand {
+        copies[tile as usize] += 1;
+    }
+    let honors = copies[27..].iter().sum::<u32>();
+    let terminals = (0..3)
+        .map(|suit| copies[suit * 9] + copies[suit * 9 + 8])
+        .sum::<u32>();
+    let suits = (0..3)
+        .filter(|suit| copies[suit * 9..suit * 9 + 9].iter().any(|&n| n > 0))
+        .count();
+    let shape = shape_of(&handle.hand);
+    // Chiitoi and kokushi are won on a single tile
+    let pair_wait = shape != Shape::Standard || pair_wait(&copies, handle.hand[13] as usize);
+    let triplets = copies.iter().filter(|&&n| n >= 3).count();
+    [
+        honors as f64,
+        terminals as f64,
+        copies.iter().filter(|&&n| n >= 2).count() as f64,
+        triplets as f64,
+        suits as f64,
+        (honors + terminals == 0) as u8 as f64,
+        (handle.flags == store::MASK_FALSE_IF_RON) as u8 as f64,
+        pair_wait as u8 as f64,
+        // An honor can not be part of a run
+        copies[31..].iter().filter(|&&n| n >= 3).count() as f64,
+        (shape == Shape::Chiitoi) as u8 as f64,
+        (suits == 1 && honors > 0) as u8 as f64,
+        (suits == 1 && honors == 0) as u8 as f64,
+        (copies.iter().filter(|&&n| n > 0).count() == 5 && triplets == 4) as u8 as f64,
+    ]
+}
+
+// Regularized log-likelihood of the weights, with its gradient and curvature
+fn objective(
+    sets: &[(Vec<Features>, Features, f64)],
+    total: f64,
+    weights: &Features,
+) -> (f64, Features, Curvature) {
+    let dot = |x: &Features| x.iter().zip(weights).map(|(x, w)| x * w).sum::<f64>();
+    let mut value = -REGULARIZATION / 2.0 * dot(weights);
+    let mut gradient = weights.map(|w| -REGULARIZATION * w);
+    let mut curvature: Curvature =
+        std::array::from_fn(|i| std::array::from_fn(|j| (i == j) as u8 as f64 * REGULARIZATION));
+    for (candidates, observed, n) in sets {
+        // First and second moments of the features of an answer under the
+        // weights
+        let dots = candidates.iter().map(dot).collect::<Vec<_>>();
+        let max = dots.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let mut z = 0.0;
+        let mut expected = [0.0; FEATURES.len()];
+        let mut second = [[0.0; FEATURES.len()]; FEATURES.len()];
+        for (x, dot) in candidates.iter().zip(dots) {
+            let p = (dot - max).exp();
+            z += p;
+            for i in 0..FEATURES.len() {
+                expected[i] += p * x[i];
+                for j in 0..FEATURES.len() {
+                    second[i][j] += p * x[i] * x[j];
+                }
+            }
+        }
+        value += (dot(observed) - n * (max + f64::ln(z))) / total;
+        for i in 0..FEATURES.len() {
+            gradient[i] += (observed[i] - n * expected[i] / z) / total;
+            for j in 0..FEATURES.len() {
+                let covariance = second[i][j] / z - expected[i] * expected[j] / (z * z);
+                curvature[i][j] += n * covariance / total;
+            }
+        }
+    }
+    (value, gradient, curvature)
+}
+
+// Solves `matrix * x = vector` by Gaussian elimination, the matrix being
+// positive definite
+fn solve(mut matrix: Curvature, mut vector: Features) -> Features {
+    let n = FEATURES.len();
+    for k in 0..n {
+        for i in k + 1..n {
+            let pivot = matrix[k];
+            let factor = matrix[i][k] / pivot[k];
+            for (a, b) in matrix[i][k..].iter_mut().zip(&pivot[k..]) {
+                *a -= factor * b;
+            }
+            vector[i] -= factor * vector[k];
+        }
+    }
+    let mut x = [0.0; FEATURES.len()];
+    for k in (0..n).rev() {
+        let rest = (k + 1..n).map(|j| matrix[k][j] * x[j]).sum::<f64>();
+        x[k] = (vector[k] - rest) / matrix[k][k];
+    }
+    x
+}
+
+// Maximum likelihood model of the past answers, each pair holding the
+// candidates of a context and the answers drawn from them
+pub fn fit(inc: impl Fn(), history: &[(Vec<Handle>, Vec<Handle>)]) -> Model {
+    let history = history
+        .iter()
+        .filter(|(_, answers)| !answers.is_empty())
+        .map(|(candidates, answers)| (candidates.iter().map(features).collect::<Vec<_>>(), answers))
+        .collect::<Vec<_>>();
+    let total = history
+        .iter()
+        .map(|(_, answers)| answers.len())
+        .sum::<usize>() as f64;
+    if total == 0.0 {
+        return Model {
+            weights: [0.0; FEATURES.len()],
+        };
+    }
+
+    // Standardized over the candidates, so that the regularization weighs
+    // counts and indicators alike; a constant feature is left out
+    let all = history.iter().flat_map(|(candidates, _)| candidates);
+    let count = all.clone().count() as f64;
+    let mut mean = [0.0; FEATURES.len()];
+    let mut scale = [0.0; FEATURES.len()];
+    for x in all.clone() {
+        for (sum, x) in mean.iter_mut().zip(x) {
+            *sum += x / count;
+        }
+    }
+    for x in all {
+        for ((sum, x), mean) in scale.iter_mut().zip(x).zip(mean) {
+            *sum += (x - mean).powi(2) / count;
+        }
+    }
+    let scale = scale.map(|var| if var > 1e-12 { 1.0 / var.sqrt() } else { 0.0 });
+    let standardize =
+        |x: Features| -> Features { std::array::from_fn(|i| (x[i] - mean[i]) * scale[i]) };
+    let sets = history
+        .into_iter()
+        .map(|(candidates, answers)| {
+            let mut observed = [0.0; FEATURES.len()];
+            for answer in answers {
+                for (sum, x) in observed.iter_mut().zip(standardize(features(answer))) {
+                    *sum += x;
+                }
+            }
+            let candidates = candidates.into_iter().map(standardize).collect::<Vec<_>>();
+            (candidates, observed, answers.len() as f64)
+        })
+        .collect::<Vec<_>>();
+
+    // Newton's method, halving the step until it gains enough
+    let mut weights = [0.0; FEATURES.len()];
+    let (mut value, mut gradient, mut curvature) = objective(&sets, total, &weights);
+    for _ in 0..FIT_STEPS {
+        if gradient.iter().map(|g| g * g).sum::<f64>().sqrt() < TOLERANCE {
+            break;
+        }
+        let direction = solve(curvature, gradient);
+        let slope = direction
+            .iter()
+            .zip(gradient)
+            .map(|(d, g)| d * g)
+            .sum::<f64>();
+        let mut step = 1.0;
+        loop {
+            let next: Features = std::array::from_fn(|i| weights[i] + step * direction[i]);
+            let (next_value, next_gradient, next_curvature) = objective(&sets, total, &next);
+            if next_value >= value + step * slope / 4.0 || step < 1e-12 {
+                (weights, value) = (next, next_value);
+                (gradient, curvature) = (next_gradient, next_curvature);
+                break;
+            }
+            step /= 2.0;
+        }
+        inc();
+    }
+    Model {
+        weights: std::array::from_fn(|i| weights[i] * scale[i]),
+    }
+}
+
+// Log-linear weight of the features of a hand
+#[derive(Debug, Clone, PartialEq)]
+pub struct Model {
+    pub weights: [f64; FEATURES.len()],
+}
+
+impl Model {
+    pub fn weight(&self, handle: &Handle) -> f64 {
+        features(handle)
+            .iter()
+            .zip(&self.weights)
+            .map(|(x, w)| x * w)
+            .sum::<f64>()
+            .exp()
+    }
+}
+
+// How likely each candidate is to be the answer, up to a factor
+#[derive(Debug, Clone, PartialEq)]
+pub enum Prior {
+    Uniform,
+    // Hands missing from the table weigh 1
+    Table(HashMap<Hand, f64>),
+    Model(Model),
+}
+
+impl Prior {
+    pub fn weight(&self, handle: &Handle) -> f64 {
+        match self {
+            Prior::Uniform => 1.0,
+            Prior::Table(table) => table.get(&handle.hand).copied().unwrap_or(1.0),
+            Prior::Model(model) => model.weight(handle),
+        }
+    }
+
+    // Weight of each handle, computed once per candidate set as a model
+    // decomposes every hand it weighs
+    pub fn weights(&self, hs: &[Handle]) -> Vec<f64> {
+        hs.iter().map(|handle| self.weight(handle)).collect()
+    }
+
+    // Either {"model": {feature: weight}} or {"table": {hand: weight}}, the
+    // hands of a table being well formed and their weights positive
+    pub fn from_json(json: &serde_json::Value) -> Option<Self> {
+        if let Some(model) = json.get("model") {
+            let mut weights = [0.0; FEATURES.len()];
+            for (weight, name) in weights.iter_mut().zip(FEATURES) {
+                *weight = model.get(name).map_or(Some(0.0), |w| w.as_f64())?;
+            }
+            if !weights.iter().all(|w| w.is_finite()) {
+                return None;
+            }
+            return Some(Prior::Model(Model { weights }));
+        }
+        let table = json
+            .get("table")?
+            .as_object()?
+            .iter()
+            .map(|(hand, weight)| {
+                let weight = weight.as_f64().filter(|w| w.is_finite() && *w > 0.0)?;
+                Some((Handle::parse_hand(hand)?, weight))
+            })
+            .collect::<Option<_>>()?;
+        Some(Prior::Table(table))
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Prior::Uniform => serde_json::json!({ "model": {} }),
+            Prior::Table(table) => {
+                let table = table
+                    .iter()
+                    .map(|(hand, &weight)| (Handle::hand_to_string(hand), weight.into()))
+                    .collect::<serde_json::Map<_, _>>();
+                serde_json::json!({ "table": table })
+            }
+            Prior::Model(model) => {
+                let weights = FEATURES
+                    .iter()
+                    .zip(model.weights)
+                    .map(|(name, weight)| (name.to_string(), weight.into()))
+                    .collect::<serde_json::Map<_, _>>();
+                serde_json::json!({ "model": weights })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::test_handles;
+
+    #[test]
+    fn test_prior() {
+        let handle = Handle::from_string("2235m345p345888s4m");
+        assert_eq!(
+            features(&handle),
+            [
+                0.0, 0.0, 2.0, 1.0, 3.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0
+            ]
+        );
+        let honors = Handle::from_string("11223344556677z");
+        assert_eq!(features(&honors)[..3], [14.0, 0.0, 7.0]);
+        assert_eq!(features(&honors)[7..10], [1.0, 0.0, 1.0]);
+        // 3m is held twice but won on a run
+        let pair_wait = |hand| features(&Handle::from_string(hand))[7];
+        assert_eq!(pair_wait("12345m345p888s55z3m"), 0.0);
+        // A tanki on 1m, or a ryanmen on 23m
+        assert_eq!(pair_wait("1123m345p345888s1m"), 0.0);
+        assert_eq!(pair_wait("1112m345p345888s2m"), 1.0);
+        assert_eq!(pair_wait("123m345p345s2z666z2z"), 1.0);
+        let yaku = features(&Handle::from_string("111222m555z6z777z6z"));
+        assert_eq!(yaku[7..], [1.0, 2.0, 0.0, 1.0, 0.0, 1.0]);
+        let chinitsu = features(&Handle::from_string("1112345678999m5m"));
+        assert_eq!(chinitsu[10..], [0.0, 1.0, 0.0]);
+
+        let mut weights = [0.0; FEATURES.len()];
+        weights[0] = 0.5;
+        let prior = Prior::Model(Model { weights });
+        assert_eq!(prior.weight(&handle), 1.0);
+        assert!((prior.weight(&honors) - 7f64.exp()).abs() < 1e-9);
+        assert_eq!(Prior::from_json(&prior.to_json()), Some(prior));
+        assert_eq!(
+            Prior::from_json(&Prior::Uniform.to_json())
+                .unwrap()
+                .weight(&honors),
+            1.0
+        );
+
+        let table = Prior::Table(HashMap::from([(handle.hand, 3.0)]));
+        assert_eq!(Prior::from_json(&table.to_json()), Some(table.clone()));
+        assert_eq!(table.weight(&handle), 3.0);
+        assert_eq!(table.weight(&honors), 1.0);
+        for weight in [0.0, -1.0] {
+            let json = serde_json::json!({ "table": { "2235m345p345888s4m": weight } });
+            assert_eq!(Prior::from_json(&json), None);
+        }
+        for hand in [
+            "2235m345p345888s",
+            "2235m345p345888s44",
+            "2235m345p345888s9z",
+        ] {
+            let json = serde_json::json!({ "table": { hand: 1.0 } });
+            assert_eq!(Prior::from_json(&json), None);
+        }
+    }
+
+    #[test]
+    fn test_fit() {
+        let candidates = test_handles(4);
+        let uniform = fit(|| (), &[(candidates.clone(), vec![])]);
+        assert_eq!(uniform.weights, [0.0; FEATURES.len()]);
+
+        // Honors came up twice as often as anything else
+        let answers = vec![candidates[3], candidates[3], candidates[0]];
+        let model = fit(|| (), &[(candidates.clone(), answers)]);
+        let weights = candidates
+            .iter()
+            .map(|h| model.weight(h))
+            .collect::<Vec<_>>();
+        let z = weights.iter().sum::<f64>();
+        assert!(weights[3] / z > 0.5);
+        assert!(weights[3] > weights[0] && weights[0] > weights[1]);
+    }
+    #[test]
+    fn test_fit_realistic() {
+        use crate::generator::Unit;
+        use riichi::tile::Tile;
+        use std::cell::Cell;
+
+        // Every twentieth hand of a unit, still thousands of real hands
+        let candidates = Unit::Standard {
+            toitsu: Tile::_5z,
+            winning_tile: Tile::_9s,
+        }
+        .run::<Handle>(None)
+        .into_iter()
+        .step_by(20)
+        .collect::<Vec<_>>();
+        let mut weights = [0.0; FEATURES.len()];
+        weights[0] = 0.3;
+        weights[4] = -0.5;
+        weights[7] = 1.0;
+        let truth = Model { weights };
+        // Each hand drawn as often as the true model expects
+        let z = candidates.iter().map(|h| truth.weight(h)).sum::<f64>();
+        let answers = candidates
+            .iter()
+            .flat_map(|h| {
+                let n = (truth.weight(h) / z * 10.0 * candidates.len() as f64).round();
+                std::iter::repeat_n(*h, n as usize)
+            })
+            .collect::<Vec<_>>();
+        let steps = Cell::new(0);
+        let model = fit(|| steps.set(steps.get() + 1), &[(candidates, answers)]);
+        // Newton steps converge in a handful
+        assert!(steps.get() < 10);
+        for (fitted, truth) in model.weights.iter().zip(truth.weights) {
+            assert!((fitted - truth).abs() < 0.1, "{:?}", model.weights);
+        }
+    }
+}
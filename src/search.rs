@@ -0,0 +1,141 @@
+use std::collections::HashSet;
+
+use super::handle::{Hand, Handle};
+use super::mahd_fast2::ScoreTable;
+
+// Best guesses of the approximation climbed from
+pub const SEEDS: usize = 64;
+
+// What the game accepts as a guess
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rules {
+    // Only the winning hands of the guess pool, nothing is searched
+    pub winning: bool,
+    // Copies of a tile a guess may hold
+    pub max_copies: u8,
+    // The first 13 tiles in order, the winning tile after them
+    pub sorted: bool,
+}
+
+impl Rules {
+    pub fn new() -> Self {
+        Rules {
+            winning: true,
+            max_copies: 4,
+            sorted: true,
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "winning" => Some(Rules::new()),
+            // Any 14 tiles of a set
+            "tiles" => Some(Rules {
+                winning: false,
+                ..Rules::new()
+            }),
+            // Any tile at any position
+            "any" => Some(Rules {
+                winning: false,
+                max_copies: 14,
+                sorted: false,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn normalize(&self, hand: &mut Hand) {
+        if self.sorted {
+            hand[..13].sort_unstable();
+        }
+    }
+
+    pub fn is_valid(&self, hand: &Hand) -> bool {
+        let mut copies = [0; 34];
+        for &tile in hand {
+            copies[tile as usize] += 1;
+        }
+        copies.iter().all(|&n| n <= self.max_copies) && (!self.sorted || hand[..13].is_sorted())
+    }
+}
+
+// Moves to the best hand one tile away, by the table, until none is better
+fn climb_one(table: &ScoreTable, seed: &Hand, rules: &Rules) -> Hand {
+    let mut hand = *seed;
+    let mut score = table.score(&hand);
+    loop {
+        let mut best = None;
+        for pos in 0..14 {
+            for tile in 0..34 {
+                let mut next = hand;
+                next[pos] = tile;
+                rules.normalize(&mut next);
+                if next == hand || !rules.is_valid(&next) {
+                    continue;
+                }
+                let next_score = table.score(&next);
+                // Ties are left alone, so every move strictly improves
+                if next_score > best.map_or(score, |(_, s)| s) + 1e-6 {
+                    best = Some((next, next_score));
+                }
+            }
+        }
+        match best {
+            Some((next, next_score)) => (hand, score) = (next, next_score),
+            None => return hand,
+        }
+    }
+}
+
+// Hands the climb reached from `seeds` other than the seeds themselves
+pub fn climb(inc: impl Fn(), table: &ScoreTable, seeds: &[Handle], rules: &Rules) -> Vec<Handle> {
+    let mut seen = seeds.iter().map(|seed| seed.hand).collect::<HashSet<_>>();
+    let mut result = vec![];
+    for seed in seeds {
+        inc();
+        if !rules.is_valid(&seed.hand) {
+            continue;
+        }
+        let hand = climb_one(table, &seed.hand, rules);
+        if seen.insert(hand) {
+            result.push(Handle {
+                hand,
+                pool: [false; 34],
+                flags: 0,
+            });
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::test_handles;
+    use crate::mahd_fast2::{MetaMap, mahd_fast2_prepare};
+
+    #[test]
+    fn test_climb() {
+        let rules = Rules::parse("tiles").unwrap();
+        assert!(rules.is_valid(&Handle::from_string("2235m345p345888s4m").hand));
+        assert!(!rules.is_valid(&Handle::from_string("22222m345p34588s4m").hand));
+        assert!(!rules.is_valid(&Handle::from_string("2253m345p345888s4m").hand));
+        assert!(
+            Rules::parse("any")
+                .unwrap()
+                .is_valid(&Handle::from_string("2253m345p345888s4m").hand)
+        );
+
+        let hs = test_handles(4);
+        let table = ScoreTable::from_pairs(&mahd_fast2_prepare(&MetaMap::from_handles(
+            || (),
+            &hs,
+            &[1.0; 4],
+        )));
+        let seeds = vec![hs[3]];
+        let result = climb(|| (), &table, &seeds, &rules);
+        assert_eq!(result.len(), 1);
+        assert!(rules.is_valid(&result[0].hand));
+        assert!(table.score(&result[0].hand) > table.score(&hs[3].hand));
+    }
+}
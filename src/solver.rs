@@ -0,0 +1,496 @@
+use std::borrow::Cow;
+use std::cell::OnceCell;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use super::call_with_progress;
+use super::classes::Classes;
+use super::handle::{ColorResult, Hand, Handle};
+use super::lookahead::{self, Lookahead};
+use super::mahd_fast2::{
+    self, Approximation, HandleScore, MetaMap, ScoreTable, Scoring, mahd_chain_prepare,
+    mahd_fast2_prepare, mahd_fast2_stream, mahd_killer, mahd_killer_inner, mahd_killer_prepare,
+    partition, score_partition,
+};
+use super::optimise;
+use super::prior::Prior;
+use super::search::{self, Rules};
+use super::tree::{self, Objective, Optimal};
+
+// Candidates times the guesses scored exactly, see the evaluate command
+pub const KILLER_BUDGET: usize = 10000000;
+
+// Choices that hold for a whole session
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub scoring: Scoring,
+    // Pre-ranking of the guesses before their exact partition
+    pub approximation: Approximation,
+    // Bits of entropy traded for a sure win, see `Scoring::cmp`
+    pub win_weight: f64,
+    // Score guesses exactly until this long after the search started, instead
+    // of a fixed number of them
+    pub think: Option<Duration>,
+    // Only guess hands consistent with every result so far
+    pub hard: bool,
+    // Guesses searched ahead for small candidate sets, 0 to disable
+    pub lookahead: usize,
+    // Where to write the decision tree of small candidate sets
+    pub export_tree: Option<String>,
+    // Guesses the game accepts, searched beyond the guess pool unless only
+    // winning hands are
+    pub rules: Rules,
+    // Best guesses grown by a move at a time after the exact scoring, 0 to
+    // disable
+    pub optimise: usize,
+    // Weight of each candidate as the answer
+    pub prior: Prior,
+}
+
+impl Policy {
+    pub fn new() -> Self {
+        Policy {
+            scoring: Scoring::Entropy,
+            approximation: Approximation::Pairs,
+            win_weight: 0.0,
+            think: None,
+            hard: false,
+            lookahead: 2,
+            export_tree: None,
+            rules: Rules::new(),
+            optimise: 0,
+            prior: Prior::Uniform,
+        }
+    }
+
+    fn objective(&self) -> Objective {
+        match self.scoring {
+            Scoring::Entropy => Objective::Expected,
+            Scoring::Minimax | Scoring::MinimaxEntropy => Objective::Worst,
+        }
+    }
+
+    // Every setting the guesses of a book depend on, see `Book`
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "scoring": format!("{:?}", self.scoring),
+            "approximation": format!("{:?}", self.approximation),
+            "think": self.think.map(|think| think.as_secs_f64()),
+            "lookahead": self.lookahead,
+            "optimise": self.optimise,
+            "hard": self.hard,
+            "win_weight": self.win_weight,
+            "rules": {
+                "winning": self.rules.winning,
+                "max_copies": self.rules.max_copies,
+                "sorted": self.rules.sorted,
+            },
+            "prior": self.prior.to_json(),
+        })
+    }
+}
+
+// Solves the candidates exactly, trying only themselves and `guesses`,
+// returning the first guess
+fn endgame(hs: &Vec<Handle>, weights: &[f64], guesses: &Vec<Handle>, policy: &Policy) -> Handle {
+    let mut optimal = Optimal::new(hs, guesses, policy.objective(), policy.hard, weights);
+    let tree = optimal.tree();
+    println!(
+        "optimal over {} guesses: {} {}",
+        optimal.n_guesses(),
+        Handle::hand_to_string(&tree.guess.hand),
+        optimal.cost()
+    );
+    if let Some(path) = &policy.export_tree {
+        std::fs::write(path, serde_json::to_string_pretty(&tree.to_json()).unwrap()).unwrap();
+    }
+    tree.guess
+}
+
+// The candidates left, their weights and their counts for the approximation,
+// updated together as results come in. The weights are only computed once.
+pub struct Candidates {
+    pub hs: Vec<Handle>,
+    // Prior of each of `hs`, in the same order
+    pub weights: Vec<f64>,
+    pub meta_map: MetaMap,
+}
+
+impl Candidates {
+    pub fn new(hs: Vec<Handle>, prior: &Prior) -> Self {
+        let weights = prior.weights(&hs);
+        let meta_map = call_with_progress!(
+            "Preparing Meta Map",
+            hs.len(),
+            MetaMap::from_handles,
+            &hs,
+            &weights
+        );
+        Candidates {
+            hs,
+            weights,
+            meta_map,
+        }
+    }
+
+    // The counts of the eliminated handles are taken off, unless counting the
+    // survivors again is cheaper
+    pub fn filter(self, guess: &Handle, result: &ColorResult) -> Self {
+        let (kept, eliminated): (Vec<_>, Vec<_>) = self
+            .hs
+            .into_iter()
+            .zip(self.weights)
+            .partition(|(handle, _)| handle.match_color_result(guess, result));
+        let (hs, weights): (Vec<_>, Vec<_>) = kept.into_iter().unzip();
+        let meta_map = if eliminated.len() < hs.len() {
+            let mut meta_map = self.meta_map;
+            for (handle, weight) in &eliminated {
+                meta_map.unregister(handle, *weight);
+            }
+            meta_map
+        } else {
+            MetaMap::from_handles(|| (), &hs, &weights)
+        };
+        Candidates {
+            hs,
+            weights,
+            meta_map,
+        }
+    }
+
+    // Chance of each candidate to be the answer
+    pub fn probabilities(&self) -> Vec<f64> {
+        let total = self.weights.iter().sum::<f64>();
+        self.weights.iter().map(|weight| weight / total).collect()
+    }
+}
+
+fn score_table(meta_map: &MetaMap, approximation: Approximation) -> ScoreTable {
+    match approximation {
+        Approximation::Pairs => ScoreTable::from_pairs(&mahd_fast2_prepare(meta_map)),
+        Approximation::Chain => ScoreTable::from_chain(&mahd_chain_prepare(meta_map)),
+    }
+}
+
+// Best `size` guesses in `hs_all` by the table, one per class if given,
+// without keeping the entropy of every guess
+pub fn approximate_top(
+    table: &ScoreTable,
+    hs_all: &Vec<Handle>,
+    size: usize,
+    classes: Option<&Classes>,
+) -> Vec<Handle> {
+    call_with_progress!(
+        "Finding Best Guess",
+        hs_all.len().div_ceil(mahd_fast2::BATCH_SIZE),
+        mahd_fast2_stream,
+        hs_all,
+        table,
+        size,
+        classes,
+    )
+}
+
+// Scores guesses exactly in the given order until `deadline`, the first one
+// whatever the time
+fn mahd_killer_until(
+    inc: impl Fn(),
+    handles: &Vec<Handle>,
+    guesses: impl Iterator<Item = Handle>,
+    deadline: Instant,
+    weights: &[f64],
+) -> Vec<HandleScore> {
+    let mut hes = vec![];
+    for guess in guesses {
+        if !hes.is_empty() && Instant::now() >= deadline {
+            break;
+        }
+        inc();
+        hes.push(score_partition(
+            &guess,
+            &partition(handles, &guess, weights),
+        ));
+    }
+    hes
+}
+
+// Every guess of a session, their hands only collected once looked up
+pub struct Pool {
+    pub hs: Vec<Handle>,
+    hands: OnceCell<HashSet<Hand>>,
+}
+
+impl Pool {
+    pub fn new(hs: Vec<Handle>) -> Self {
+        Pool {
+            hs,
+            hands: OnceCell::new(),
+        }
+    }
+
+    pub fn hands(&self) -> &HashSet<Hand> {
+        self.hands
+            .get_or_init(|| self.hs.iter().map(|handle| handle.hand).collect())
+    }
+}
+
+// `pool` is unused in hard mode, where the candidates are the guess pool
+pub fn best_guess(candidates: &Candidates, pool: &Pool, policy: &Policy) -> Handle {
+    let (hs, weights) = (&candidates.hs, &candidates.weights);
+    let hs_all = if policy.hard { hs } else { &pool.hs };
+
+    let guess_opt = if hs.len() < 1000 {
+        // finding inner killer
+        mahd_killer_inner(hs)
+    } else {
+        None
+    };
+
+    if let Some(guess) = guess_opt {
+        // Separating every candidate is optimal for either objective
+        if hs.len() <= tree::MAX_CANDIDATES {
+            return endgame(hs, weights, &vec![guess], policy);
+        }
+        return guess;
+    }
+
+    // finding best guess
+    let start = Instant::now();
+    // At least one guess is scored however many candidates remain
+    let n_killer_candidate = (KILLER_BUDGET / hs.len()).max(1);
+    let meta_map = &candidates.meta_map;
+
+    // Guesses splitting the candidates alike are ranked once
+    let classes = Classes::new(hs);
+    let classes = Some(&classes).filter(|classes| !classes.is_trivial());
+
+    // Live candidates may win, so they are scored too when affordable
+    let mut extra_guesses = if policy.win_weight > 0.0 && hs.len() <= n_killer_candidate {
+        hs.clone()
+    } else {
+        vec![]
+    };
+
+    // Then the arrangements beyond the pool the approximation prefers
+    if !policy.hard && !policy.rules.winning {
+        let table = score_table(meta_map, policy.approximation);
+        let seeds = approximate_top(&table, hs_all, search::SEEDS, classes);
+        let searched = call_with_progress!(
+            "Searching Guesses",
+            seeds.len(),
+            search::climb,
+            &table,
+            &seeds,
+            &policy.rules,
+        );
+        println!("{} guesses found beyond the pool", searched.len());
+        extra_guesses.extend(searched);
+    }
+    let mut hands = HashSet::new();
+    extra_guesses.retain(|handle| hands.insert(handle.hand));
+
+    // finding killer
+    let mut hes = match policy.think {
+        // Best first, as far as the time allows. The best `size` of the pool
+        // are streamed, four times as many each round ending before the
+        // deadline, the extra guesses ranked among them by the same table.
+        Some(think) => {
+            let deadline = start + think;
+            let table = score_table(meta_map, policy.approximation);
+            let score = |handle: &Handle| table.score(&handle.hand);
+            let mut scored = HashSet::new();
+            let mut hes = vec![];
+            let mut size = n_killer_candidate;
+            loop {
+                let exhausted = size >= hs_all.len();
+                let mut guesses = approximate_top(&table, hs_all, size, classes);
+                let threshold = match guesses.last() {
+                    Some(last) if !exhausted => score(last),
+                    _ => f32::NEG_INFINITY,
+                };
+                guesses.retain(|handle| !hands.contains(&handle.hand));
+                guesses.extend(
+                    extra_guesses
+                        .iter()
+                        .filter(|handle| score(handle) >= threshold),
+                );
+                guesses.retain(|handle| scored.insert(handle.hand));
+                guesses.sort_by(|a, b| score(b).total_cmp(&score(a)));
+                hes.extend(call_with_progress!(
+                    "Finding Killer Until Deadline",
+                    guesses.len(),
+                    mahd_killer_until,
+                    hs,
+                    guesses.into_iter(),
+                    deadline,
+                    weights,
+                ));
+                if exhausted || Instant::now() >= deadline {
+                    break;
+                }
+                size = size.saturating_mul(4);
+            }
+            println!("scored {} guesses in {:?}", hes.len(), start.elapsed());
+            hes
+        }
+        None => {
+            let table = score_table(meta_map, policy.approximation);
+            let mut result = approximate_top(&table, hs_all, n_killer_candidate, classes);
+            result.retain(|handle| !hands.contains(&handle.hand));
+            let result = extra_guesses.into_iter().chain(result).collect::<Vec<_>>();
+            call_with_progress!(
+                "Finding Killer Prepare",
+                result.len(),
+                mahd_killer_prepare,
+                hs,
+                &result,
+                weights,
+            )
+        }
+    };
+
+    // Neighbours of the best guess the approximation ranked too low
+    let best = hes
+        .iter()
+        .max_by(|a, b| a.entropy.total_cmp(&b.entropy))
+        .filter(|_| policy.optimise > 0);
+    if let Some(best) = best {
+        let seed = Handle {
+            hand: best.hand,
+            pool: [false; 34],
+            flags: 0,
+        };
+        // The hands of the pool are only looked up under winning rules
+        let (rules, hands) = if policy.hard {
+            let hands = hs.iter().map(|handle| handle.hand).collect();
+            (Rules::new(), Cow::Owned(hands))
+        } else if policy.rules.winning {
+            (policy.rules, Cow::Borrowed(pool.hands()))
+        } else {
+            (policy.rules, Cow::Owned(HashSet::new()))
+        };
+        let (found, counters) = call_with_progress!(
+            "Optimising Guess",
+            policy.optimise,
+            optimise::optimise,
+            hs,
+            weights,
+            &hands,
+            &seed,
+            &rules,
+            policy.optimise,
+        );
+        println!(
+            "expanded {} pushed {} duplicates {} evicted {}",
+            counters.expansions, counters.pushed, counters.duplicates, counters.evicted
+        );
+        let seen = hes.iter().map(|he| he.hand).collect::<HashSet<_>>();
+        hes.extend(
+            found
+                .into_iter()
+                .filter(|score| !seen.contains(&score.hand)),
+        );
+    }
+
+    // Every guess scored, for the exact tree
+    let scored = hes
+        .iter()
+        .map(|he| Handle {
+            hand: he.hand,
+            pool: [false; 34],
+            flags: 0,
+        })
+        .collect::<Vec<_>>();
+
+    let result = call_with_progress!(
+        "Finding Killer",
+        hes.len(),
+        mahd_killer,
+        hes,
+        10,
+        policy.scoring,
+        policy.win_weight,
+    );
+
+    for handle in &result {
+        println!(
+            "{} {} {} {}",
+            Handle::hand_to_string(&handle.hand),
+            handle.entropy,
+            handle.max_bucket,
+            handle.win
+        );
+    }
+
+    let killers = result
+        .iter()
+        .map(|handle| Handle {
+            hand: handle.hand,
+            pool: [false; 34],
+            flags: 0,
+        })
+        .collect::<Vec<_>>();
+
+    // Guesses splitting the candidates alike are tried once, which keeps
+    // passing all of them cheap
+    if hs.len() <= tree::MAX_CANDIDATES {
+        return endgame(hs, weights, &scored, policy);
+    }
+
+    // Expected guesses only agree with entropy scoring
+    if policy.lookahead > 0
+        && policy.scoring == Scoring::Entropy
+        && hs.len() <= lookahead::MAX_CANDIDATES
+    {
+        let mut search = Lookahead::new(hs, &killers, lookahead::TOP_K, policy.hard, weights);
+        if let Some((guess, expected)) = search.best_guess(policy.lookahead) {
+            println!(
+                "lookahead: {} {}",
+                Handle::hand_to_string(&guess.hand),
+                expected
+            );
+            return guess;
+        }
+    }
+
+    killers[0]
+}
+
+// Every answer is a possible opener: the approximation picks `n_candidate` of
+// them, the best `size` by exact partition come first
+pub fn best_openers(
+    hs: &Vec<Handle>,
+    n_candidate: usize,
+    size: usize,
+    policy: &Policy,
+) -> Vec<HandleScore> {
+    let weights = policy.prior.weights(hs);
+    let meta_map = call_with_progress!(
+        "Preparing Meta Map",
+        hs.len(),
+        MetaMap::from_handles,
+        hs,
+        &weights
+    );
+    let table = score_table(&meta_map, policy.approximation);
+    let result = approximate_top(&table, hs, n_candidate, None);
+
+    let hes = call_with_progress!(
+        "Scoring Opener Candidates",
+        result.len(),
+        mahd_killer_prepare,
+        hs,
+        &result,
+        &weights,
+    );
+
+    call_with_progress!(
+        "Sorting Openers",
+        hes.len(),
+        mahd_killer,
+        hes,
+        size,
+        policy.scoring,
+        policy.win_weight,
+    )
+}
@@ -0,0 +1,357 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+use super::generator::{Detail, Shape, Wait};
+use super::handle::{Context, Hand, Handle};
+use super::store;
+
+// Records from a cache file have lost their decomposition, so a hand that is
+// also seven pairs is counted as chiitoi
+pub fn shape_of(hand: &Hand) -> Shape {
+    const ORPHANS: [usize; 13] = [0, 8, 9, 17, 18, 26, 27, 28, 29, 30, 31, 32, 33];
+    let mut counts = [0u8; 34];
+    for &tile in hand {
+        counts[tile as usize] += 1;
+    }
+    if ORPHANS.iter().all(|&tile| counts[tile] > 0) {
+        Shape::Kokushi
+    } else if counts.iter().filter(|&&count| count == 2).count() == 7 {
+        Shape::Chiitoi
+    } else {
+        Shape::Standard
+    }
+}
+
+const WAITS: [Wait; 5] = [
+    Wait::Ryanmen,
+    Wait::Kanchan,
+    Wait::Penchan,
+    Wait::Shanpon,
+    Wait::Tanki,
+];
+
+// Flags, waits and yaku bits of the decompositions of a hand seen so far
+#[derive(Clone, Copy)]
+struct Tally {
+    hand: Hand,
+    flags: u8,
+    waits: u8,
+    yaku: u64,
+}
+
+impl store::Record for Tally {
+    fn hand(&self) -> Hand {
+        self.hand
+    }
+
+    fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    fn merge(&mut self, other: &Self, flags: u8) {
+        self.flags = flags;
+        self.waits |= other.waits;
+        self.yaku |= other.yaku;
+    }
+}
+
+fn merge_into(tallies: &mut HashMap<Hand, Tally>, tally: Tally) {
+    tallies
+        .entry(tally.hand)
+        .and_modify(|kept| store::merge_record(kept, &tally))
+        .or_insert(tally);
+}
+
+pub fn flag_class(flags: u8) -> String {
+    if flags & store::MASK_TRUE_ALWAYS != 0 {
+        return "always".to_string();
+    }
+    let mut clauses = vec![];
+    let winds = "eswn"
+        .chars()
+        .enumerate()
+        .filter(|(i, _)| flags & (1 << i) != 0)
+        .map(|(_, c)| c)
+        .collect::<String>();
+    if !winds.is_empty() {
+        if flags & store::MASK_NOT_ANY != 0 {
+            clauses.push(format!("not-any-{}", winds));
+        } else {
+            clauses.push(format!("any-{}", winds));
+        }
+    }
+    if flags & store::MASK_FALSE_IF_RON != 0 {
+        clauses.push("tsumo".to_string());
+    }
+    if clauses.is_empty() {
+        "never".to_string()
+    } else {
+        clauses.join("|")
+    }
+}
+
+fn debug_keys<K: std::fmt::Debug>(map: &BTreeMap<K, u64>) -> BTreeMap<String, u64> {
+    map.iter()
+        .map(|(key, count)| (format!("{:?}", key), *count))
+        .collect()
+}
+
+pub struct Stats {
+    pub total: u64,
+    shapes: BTreeMap<Shape, u64>,
+    flags: [u64; 256],
+    waits: BTreeMap<Wait, u64>,
+    yaku: BTreeMap<String, u64>,
+    contexts: Vec<(Context, u64)>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            total: 0,
+            shapes: BTreeMap::new(),
+            flags: [0; 256],
+            waits: BTreeMap::new(),
+            yaku: BTreeMap::new(),
+            contexts: Context::all()
+                .into_iter()
+                .map(|context| (context, 0))
+                .collect(),
+        }
+    }
+
+    fn register_with_shape(&mut self, handle: &Handle, shape: Shape) {
+        self.total += 1;
+        *self.shapes.entry(shape).or_insert(0) += 1;
+        self.flags[handle.flags as usize] += 1;
+        for (context, count) in self.contexts.iter_mut() {
+            *count += context.check_flags(handle.flags) as u64;
+        }
+    }
+
+    pub fn register(&mut self, handle: &Handle) {
+        self.register_with_shape(handle, shape_of(&handle.hand));
+    }
+
+    // A hand with the merged flags of its decompositions, and the bits of
+    // their waits and yaku
+    fn count(&mut self, tally: Tally, names: &[String]) {
+        self.register(&Handle {
+            hand: tally.hand,
+            pool: [false; 34],
+            flags: tally.flags,
+        });
+        for wait in WAITS
+            .iter()
+            .filter(|&&wait| tally.waits & (1 << wait as u8) != 0)
+        {
+            *self.waits.entry(*wait).or_insert(0) += 1;
+        }
+        for (bit, name) in names.iter().enumerate() {
+            if tally.yaku & (1 << bit) != 0 {
+                *self.yaku.entry(name.clone()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    // Yaku and waits are only known while generating. A hand counts once,
+    // under every wait and yaku of any of its decompositions.
+    //
+    // The decompositions of a hand share its winning tile, and the standard
+    // units come grouped by winning tile (see `Unit::all`), so the hands of a
+    // group are counted as soon as the next one starts. Only the standard
+    // hands that are also seven pairs wait for the chiitoi units.
+    pub fn from_details(details: impl Iterator<Item = Detail>) -> Self {
+        // Yaku names by bit
+        let mut names: Vec<String> = vec![];
+        let mut stats = Stats::new();
+        let mut group: HashMap<Hand, Tally> = HashMap::new();
+        let mut group_tile = None;
+        let mut sevens: HashMap<Hand, Tally> = HashMap::new();
+        for detail in details {
+            let mut yaku = 0u64;
+            for name in detail.yaku {
+                let bit = match names.iter().position(|known| *known == name) {
+                    Some(bit) => bit,
+                    None => {
+                        names.push(name);
+                        names.len() - 1
+                    }
+                };
+                yaku |= 1 << bit;
+            }
+            let hand = detail.handle.hand;
+            let tally = Tally {
+                hand,
+                flags: detail.handle.flags,
+                waits: 1 << detail.wait as u8,
+                yaku,
+            };
+            match detail.shape {
+                Shape::Standard if shape_of(&hand) == Shape::Chiitoi => {
+                    merge_into(&mut sevens, tally);
+                }
+                Shape::Standard => {
+                    if group_tile != Some(hand[13]) {
+                        for (_, tally) in group.drain() {
+                            stats.count(tally, &names);
+                        }
+                        group_tile = Some(hand[13]);
+                    }
+                    merge_into(&mut group, tally);
+                }
+                // Merged with its standard decompositions, if any
+                Shape::Chiitoi => {
+                    merge_into(&mut sevens, tally);
+                    stats.count(sevens.remove(&hand).unwrap(), &names);
+                }
+                Shape::Kokushi => stats.count(tally, &names),
+            }
+        }
+        for (_, tally) in group.into_iter().chain(sevens) {
+            stats.count(tally, &names);
+        }
+        stats
+    }
+
+    pub fn sections(&self) -> Vec<(&'static str, BTreeMap<String, u64>)> {
+        let mut flags = BTreeMap::new();
+        for (bits, &count) in self.flags.iter().enumerate() {
+            if count > 0 {
+                *flags.entry(flag_class(bits as u8)).or_insert(0) += count;
+            }
+        }
+        let contexts = self
+            .contexts
+            .iter()
+            .map(|(context, count)| match context.key() {
+                key if key.is_empty() => ("-".to_string(), *count),
+                key => (key, *count),
+            })
+            .collect();
+        vec![
+            ("shape", debug_keys(&self.shapes)),
+            ("flags", flags),
+            ("yaku", self.yaku.clone()),
+            ("wait", debug_keys(&self.waits)),
+            ("context", contexts),
+        ]
+    }
+
+    pub fn write_table(&self, mut writer: impl Write) -> std::io::Result<()> {
+        writeln!(writer, "{:<24} {:>12}", "total", self.total)?;
+        for (section, counts) in self.sections() {
+            if counts.is_empty() {
+                continue;
+            }
+            writeln!(writer, "\n[{}]", section)?;
+            for (key, count) in counts {
+                let percent = count as f64 / self.total.max(1) as f64 * 100.0;
+                writeln!(writer, "{:<24} {:>12} {:>8.3}%", key, count, percent)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_csv(&self, writer: impl Write) -> csv::Result<()> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(["section", "key", "count"])?;
+        writer.write_record(["total", "", &self.total.to_string()])?;
+        for (section, counts) in self.sections() {
+            for (key, count) in counts {
+                writer.write_record([section, &key, &count.to_string()])?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::Map::new();
+        json.insert("total".to_string(), self.total.into());
+        for (section, counts) in self.sections() {
+            json.insert(section.to_string(), serde_json::to_value(counts).unwrap());
+        }
+        json.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flag_class() {
+        assert_eq!(flag_class(store::MASK_TRUE_ALWAYS), "always");
+        assert_eq!(flag_class(store::MASK_FALSE_IF_RON), "tsumo");
+        assert_eq!(flag_class(store::ANY_TON | store::ANY_PEI), "any-en");
+        assert_eq!(
+            flag_class(store::NOT_ANY_SHA | store::MASK_FALSE_IF_RON),
+            "not-any-w|tsumo"
+        );
+    }
+
+    #[test]
+    fn test_from_details() {
+        // 11z 234m 345m 123p 789s won on 4m, ryanmen or kanchan
+        let handle = Handle::from_string("23345m123p789s11z4m");
+        let detail = |flags, wait, yaku: &[&str]| Detail {
+            handle: Handle { flags, ..handle },
+            shape: Shape::Standard,
+            wait,
+            yaku: yaku.iter().map(|name| name.to_string()).collect(),
+        };
+        let stats = Stats::from_details(
+            [
+                detail(store::NOT_ANY_TON, Wait::Ryanmen, &["Pinfu", "Tanyao"]),
+                detail(store::MASK_FALSE_IF_RON, Wait::Kanchan, &["Tanyao"]),
+            ]
+            .into_iter(),
+        );
+        assert_eq!(stats.total, 1);
+        let sections = stats.sections();
+        assert_eq!(sections[1].1["not-any-e|tsumo"], 1);
+        assert_eq!(sections[2].1["Tanyao"], 1);
+        assert_eq!(sections[2].1["Pinfu"], 1);
+        assert_eq!(sections[3].1.values().sum::<u64>(), 2);
+
+        // Hands are counted as their winning tile group ends, and seven pairs
+        // once their chiitoi decomposition comes
+        let sevens = Handle::from_string("1122334455667m7m");
+        let other = Handle::from_string("23345m123p789s11z2m");
+        let detail = |handle: Handle, shape, wait| Detail {
+            handle: Handle {
+                flags: store::MASK_TRUE_ALWAYS,
+                ..handle
+            },
+            shape,
+            wait,
+            yaku: vec![],
+        };
+        let stats = Stats::from_details(
+            [
+                detail(handle, Shape::Standard, Wait::Ryanmen),
+                detail(sevens, Shape::Standard, Wait::Ryanmen),
+                detail(handle, Shape::Standard, Wait::Kanchan),
+                detail(other, Shape::Standard, Wait::Kanchan),
+                detail(sevens, Shape::Chiitoi, Wait::Tanki),
+            ]
+            .into_iter(),
+        );
+        assert_eq!(stats.total, 3);
+        let sections = stats.sections();
+        assert_eq!(sections[0].1["Chiitoi"], 1);
+        assert_eq!(sections[3].1["Kanchan"], 2);
+        assert_eq!(sections[3].1["Tanki"], 1);
+    }
+
+    #[test]
+    fn test_shape_of() {
+        let hand = Handle::from_string("19m19p19s1234567z1m").hand;
+        assert_eq!(shape_of(&hand), Shape::Kokushi);
+        let hand = Handle::from_string("1133m5577p22s1133z").hand;
+        assert_eq!(shape_of(&hand), Shape::Chiitoi);
+        let hand = Handle::from_string("2235m345p345888s4m").hand;
+        assert_eq!(shape_of(&hand), Shape::Standard);
+    }
+}
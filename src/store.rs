@@ -1,7 +1,22 @@
+use super::handle::{Hand, Handle};
+
 // 8..0: Flags
 // 92..8: Hand
 // 126..92: Pool
 
+// A flags byte is a disjunction of clauses: a hand is valid in a context if
+// any of its decompositions is, so merging two records ORs their clauses.
+//
+// 3..0: Winds of the wind clause
+// 4: Polarity of the wind clause (unset: any wind is active, set: any wind is
+//    inactive)
+// 6: Valid if won by tsumo, false if by ron
+// 7: Valid always
+//
+// Caches written before this format kept a single "wind inactive" clause in
+// bits 5..4 with bits 3..0 unset, a pattern this format never produces: see
+// `migrate_flags`.
+
 pub const ANY_TON: u8 = 0b00000001;
 pub const ANY_NAN: u8 = 0b00000010;
 pub const ANY_SHA: u8 = 0b00000100;
@@ -9,17 +24,119 @@ pub const ANY_PEI: u8 = 0b00001000;
 
 pub const MASK_GROUP_ANY: u8 = 0b00001111;
 
-pub const NOT_ANY_TON: u8 = 0b00000000;
-pub const NOT_ANY_NAN: u8 = 0b00010000;
-pub const NOT_ANY_SHA: u8 = 0b00100000;
-pub const NOT_ANY_PEI: u8 = 0b00110000;
+pub const MASK_NOT_ANY: u8 = 0b00010000;
 
-pub const MASK_GROUP_NOT_ANY: u8 = 0b00110000;
+pub const NOT_ANY_TON: u8 = MASK_NOT_ANY | ANY_TON;
+pub const NOT_ANY_NAN: u8 = MASK_NOT_ANY | ANY_NAN;
+pub const NOT_ANY_SHA: u8 = MASK_NOT_ANY | ANY_SHA;
+pub const NOT_ANY_PEI: u8 = MASK_NOT_ANY | ANY_PEI;
 
 pub const MASK_FALSE_IF_RON: u8 = 0b01000000;
 
 pub const MASK_TRUE_ALWAYS: u8 = 0b10000000;
 
+// Flags of a record read from a cache, in either format
+pub fn migrate_flags(flags: u8) -> u8 {
+    if flags & (MASK_TRUE_ALWAYS | MASK_FALSE_IF_RON | MASK_GROUP_ANY) != 0 {
+        return flags;
+    }
+    // Old NOT_ANY_TON to NOT_ANY_PEI were 0b00 to 0b11 in bits 5..4
+    MASK_NOT_ANY | (ANY_TON << ((flags >> 4) & 0b11))
+}
+
+// Fails on clauses whose disjunction has no encoding, which a single hand
+// should never produce
+pub fn merge_flags(flags: u8, other: u8) -> Result<u8, String> {
+    if (flags | other) & MASK_TRUE_ALWAYS != 0 {
+        return Ok(MASK_TRUE_ALWAYS);
+    }
+    let ron = (flags | other) & MASK_FALSE_IF_RON;
+    let winds = match (flags & MASK_GROUP_ANY, other & MASK_GROUP_ANY) {
+        (0, _) => other & (MASK_GROUP_ANY | MASK_NOT_ANY),
+        (_, 0) => flags & (MASK_GROUP_ANY | MASK_NOT_ANY),
+        (fst, snd) if flags & MASK_NOT_ANY == other & MASK_NOT_ANY => {
+            fst | snd | (flags & MASK_NOT_ANY)
+        }
+        // "Any of A is active" or "any of B is inactive" holds everywhere once
+        // A and B share a wind. Disjoint sets cannot come from one hand: a wind
+        // koutsu and a pinfu wind jantou need different honor tile counts.
+        (fst, snd) if fst & snd != 0 => return Ok(MASK_TRUE_ALWAYS),
+        _ => return Err(format!("mixed wind clauses {:08b} {:08b}", flags, other)),
+    };
+    Ok(ron | winds)
+}
+
+// A decomposition of a hand, merged with the others of the hand into one
+pub trait Record {
+    fn hand(&self) -> Hand;
+    fn flags(&self) -> u8;
+    // Takes the merged `flags`, and whatever else `other` adds
+    fn merge(&mut self, other: &Self, flags: u8);
+}
+
+// A packed record of a cache file
+impl Record for u128 {
+    fn hand(&self) -> Hand {
+        get_hand(self)
+    }
+
+    fn flags(&self) -> u8 {
+        migrate_flags(*self as u8)
+    }
+
+    fn merge(&mut self, _: &Self, flags: u8) {
+        *self = (*self & !0b11111111) | flags as u128;
+    }
+}
+
+impl Record for Handle {
+    fn hand(&self) -> Hand {
+        self.hand
+    }
+
+    fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    fn merge(&mut self, _: &Self, flags: u8) {
+        self.flags = flags;
+    }
+}
+
+// Merges `record` into `kept`, of the same hand. Clauses with no encoding are
+// reported and the hand is kept valid everywhere rather than dropped where it
+// may win.
+pub fn merge_record<T: Record>(kept: &mut T, record: &T) {
+    let flags = merge_flags(kept.flags(), record.flags()).unwrap_or_else(|e| {
+        println!("{}: {}", Handle::hand_to_string(&kept.hand()), e);
+        MASK_TRUE_ALWAYS
+    });
+    kept.merge(record, flags);
+}
+
+// One record per hand, sorted by hand
+pub fn merge_records<T: Record>(records: &mut Vec<T>) {
+    records.sort_unstable_by_key(T::hand);
+    records.dedup_by(|record, kept| {
+        if record.hand() != kept.hand() {
+            return false;
+        }
+        merge_record(kept, record);
+        true
+    });
+}
+
+pub const CHECKSUM_INIT: u64 = 0xcbf29ce484222325;
+
+// FNV-1a, stable across builds unlike `DefaultHasher`
+pub fn checksum(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
 pub fn set_hand(info: &mut u128, hand: [u8; 14]) {
     let mut shift = 8;
     for i in 0..14 {
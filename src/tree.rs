@@ -0,0 +1,284 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::handle::{Handle, color_result_to_index, color_result_to_string};
+
+// Candidate sets up to this size are solved exactly over the guesses offered
+pub const MAX_CANDIDATES: usize = 64;
+
+// Feedback index of the winning result
+const WIN: u32 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Objective {
+    Expected,
+    Worst,
+}
+
+// A guess and the subtree for every feedback but the winning one
+#[derive(Debug)]
+pub struct Tree {
+    pub guess: Handle,
+    pub size: usize,
+    pub branches: Vec<(String, Tree)>,
+}
+
+impl Tree {
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::Map::new();
+        json.insert(
+            "guess".to_string(),
+            Handle::hand_to_string(&self.guess.hand).into(),
+        );
+        json.insert("size".to_string(), self.size.into());
+        if !self.branches.is_empty() {
+            let branches = self
+                .branches
+                .iter()
+                .map(|(result, tree)| (result.clone(), tree.to_json()))
+                .collect::<serde_json::Map<_, _>>();
+            json.insert("branches".to_string(), branches.into());
+        }
+        json.into()
+    }
+}
+
+// Minimum total (expected) or maximum (worst-case) number of guesses over
+// every candidate, each weighing its prior in the total, subsets of `hs`
+// being stored as sorted indices. Only the candidates and the hands of `pool`
+// are tried as guesses, so the tree is optimal over those alone, not over the
+// guess pool of a session nor over every hand.
+pub struct Optimal<'a> {
+    hs: &'a Vec<Handle>,
+    weights: Vec<f64>,
+    guesses: Vec<Handle>,
+    // Feedback index of each candidate, per guess
+    partitions: Vec<Vec<u32>>,
+    // In hard mode, the candidate each guess is, if any
+    members: Option<Vec<Option<u32>>>,
+    objective: Objective,
+    memo: HashMap<Vec<u32>, (f64, usize)>,
+}
+
+impl<'a> Optimal<'a> {
+    // In hard mode a guess must agree with every feedback on its path, which
+    // leaves the candidates of the subset it is tried on
+    pub fn new(
+        hs: &'a Vec<Handle>,
+        pool: &Vec<Handle>,
+        objective: Objective,
+        hard: bool,
+        weights: &[f64],
+    ) -> Self {
+        let mut guesses = vec![];
+        let mut partitions = vec![];
+        let mut seen = HashSet::new();
+        // Guessing a candidate always splits its subset
+        for guess in pool.iter().chain(hs.iter()) {
+            let partition = hs
+                .iter()
+                .map(|handle| color_result_to_index(&handle.get_color_result(guess)))
+                .collect::<Vec<_>>();
+            // Guesses splitting the candidates alike are interchangeable, so
+            // only the first one is kept
+            let mut labels = HashMap::from([(WIN, WIN)]);
+            let shape = partition
+                .iter()
+                .map(|index| {
+                    let next = labels.len() as u32;
+                    *labels.entry(*index).or_insert(next)
+                })
+                .collect::<Vec<_>>();
+            if seen.insert(shape) {
+                guesses.push(*guess);
+                partitions.push(partition);
+            }
+        }
+        let members = hard.then(|| {
+            guesses
+                .iter()
+                .map(|guess| {
+                    hs.iter()
+                        .position(|handle| handle.hand == guess.hand)
+                        .map(|i| i as u32)
+                })
+                .collect()
+        });
+        Optimal {
+            hs,
+            weights: weights.to_vec(),
+            guesses,
+            partitions,
+            members,
+            objective,
+            memo: HashMap::new(),
+        }
+    }
+
+    fn split(&self, subset: &[u32], guess: usize) -> BTreeMap<u32, Vec<u32>> {
+        let mut buckets: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+        for &i in subset {
+            let index = self.partitions[guess][i as usize];
+            buckets.entry(index).or_default().push(i);
+        }
+        buckets
+    }
+
+    fn solve(&mut self, subset: &[u32]) -> f64 {
+        let weight = subset
+            .iter()
+            .map(|&i| self.weights[i as usize])
+            .sum::<f64>();
+        if subset.len() <= 1 {
+            return match self.objective {
+                Objective::Expected => weight,
+                Objective::Worst => subset.len() as f64,
+            };
+        }
+        if let Some(&(cost, _)) = self.memo.get(subset) {
+            return cost;
+        }
+        // Guessing the likeliest candidate, separating the others, is never
+        // beaten
+        let bound = match self.objective {
+            Objective::Expected => {
+                let max = subset
+                    .iter()
+                    .map(|&i| self.weights[i as usize])
+                    .fold(0.0, f64::max);
+                2.0 * weight - max
+            }
+            Objective::Worst => 2.0,
+        };
+        let mut best = (f64::INFINITY, 0);
+        for guess in 0..self.guesses.len() {
+            let legal = self.members.as_ref().is_none_or(|members| {
+                members[guess].is_some_and(|i| subset.binary_search(&i).is_ok())
+            });
+            if !legal {
+                continue;
+            }
+            let buckets = self.split(subset, guess);
+            if buckets.len() == 1 && !buckets.contains_key(&WIN) {
+                continue;
+            }
+            let mut cost = match self.objective {
+                Objective::Expected => weight,
+                Objective::Worst => 1.0,
+            };
+            for (index, bucket) in buckets {
+                if cost >= best.0 {
+                    break;
+                }
+                if index == WIN {
+                    continue;
+                }
+                let rest = self.solve(&bucket);
+                cost = match self.objective {
+                    Objective::Expected => cost + rest,
+                    Objective::Worst => cost.max(1.0 + rest),
+                };
+            }
+            if cost < best.0 {
+                best = (cost, guess);
+                if cost <= bound {
+                    break;
+                }
+            }
+        }
+        // Unreachable while the candidates are among the guesses
+        assert!(best.0.is_finite(), "no guess splits the candidates");
+        self.memo.insert(subset.to_vec(), best);
+        best.0
+    }
+
+    // Guesses kept after merging those that split the candidates alike
+    pub fn n_guesses(&self) -> usize {
+        self.guesses.len()
+    }
+
+    // Expected or worst-case number of guesses, counting the next one
+    pub fn cost(&mut self) -> f64 {
+        let all = (0..self.hs.len() as u32).collect::<Vec<_>>();
+        let cost = self.solve(&all);
+        match self.objective {
+            Objective::Expected => cost / self.weights.iter().sum::<f64>(),
+            Objective::Worst => cost,
+        }
+    }
+
+    fn build(&mut self, subset: &[u32]) -> Tree {
+        if subset.len() == 1 {
+            return Tree {
+                guess: self.hs[subset[0] as usize],
+                size: 1,
+                branches: vec![],
+            };
+        }
+        self.solve(subset);
+        let guess = self.memo[subset].1;
+        let branches = self
+            .split(subset, guess)
+            .into_iter()
+            .filter(|(index, _)| *index != WIN)
+            .map(|(_, bucket)| {
+                let result = self.hs[bucket[0] as usize].get_color_result(&self.guesses[guess]);
+                (color_result_to_string(&result), self.build(&bucket))
+            })
+            .collect();
+        Tree {
+            guess: self.guesses[guess],
+            size: subset.len(),
+            branches,
+        }
+    }
+
+    pub fn tree(&mut self) -> Tree {
+        let all = (0..self.hs.len() as u32).collect::<Vec<_>>();
+        self.build(&all)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::test_handles;
+
+    fn depth(tree: &Tree) -> usize {
+        1 + tree
+            .branches
+            .iter()
+            .map(|(_, t)| depth(t))
+            .max()
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn test_optimal() {
+        let hs = test_handles(3);
+
+        // Without a pool, the candidates are still offered
+        let mut optimal = Optimal::new(&hs, &vec![], Objective::Expected, false, &[1.0; 3]);
+        assert!((optimal.cost() - 5.0 / 3.0).abs() < 1e-9);
+        let tree = optimal.tree();
+        assert_eq!(tree.size, 3);
+        assert_eq!(tree.branches.len(), 2);
+        assert_eq!(depth(&tree), 2);
+
+        let mut optimal = Optimal::new(&hs, &hs, Objective::Worst, false, &[1.0; 3]);
+        assert_eq!(optimal.cost(), 2.0);
+        assert_eq!(optimal.n_guesses(), 3);
+        let json = optimal.tree().to_json();
+        assert_eq!(json["branches"].as_object().unwrap().len(), 2);
+
+        // A guess off the candidates is never played in hard mode
+        let pool = vec![Handle::from_string("123456789p11s123m")];
+        let mut optimal = Optimal::new(&hs, &pool, Objective::Expected, true, &[1.0; 3]);
+        assert!((optimal.cost() - 5.0 / 3.0).abs() < 1e-9);
+        assert!(hs.contains(&optimal.tree().guess));
+
+        // The likeliest candidate is guessed first
+        let mut optimal = Optimal::new(&hs, &vec![], Objective::Expected, false, &[1.0, 1.0, 8.0]);
+        assert!((optimal.cost() - 1.2).abs() < 1e-9);
+        assert_eq!(optimal.tree().guess, hs[2]);
+    }
+}
@@ -3,6 +3,18 @@ use riichi::tile_group::{KoutsuGroup, ShuntsuGroup, TileGroup, ToitsuGroup};
 
 pub const STYLE: &str = "[{elapsed_precise}]-[{eta_precise}] {bar:40} {pos}/{len} {msg}";
 
+#[macro_export]
+macro_rules! call_with_progress {
+    ($name:expr, $total:expr, $func:expr, $($args:expr),* $(,)?) => {{
+        let pb = ::indicatif::ProgressBar::new($total as u64);
+        pb.set_style(::indicatif::ProgressStyle::with_template($crate::utils::STYLE).unwrap());
+        pb.set_message($name);
+        let result = $func(&|| pb.inc(1), $($args,)*);
+        pb.finish_with_message($name.to_string() + &" done");
+        result
+    }};
+}
+
 pub fn next_tile(tile: Tile) -> Option<Tile> {
     use Tile::*;
     let tile = match tile {
@@ -44,6 +56,10 @@ pub fn next_tile(tile: Tile) -> Option<Tile> {
     Some(tile)
 }
 
+pub fn all_tiles() -> impl Iterator<Item = Tile> {
+    std::iter::successors(Some(Tile::_1m), |&tile| next_tile(tile))
+}
+
 pub fn toitsu_of_tile(tile: Tile) -> TileGroup {
     use Tile::*;
     use ToitsuGroup::*;